@@ -1,5 +1,6 @@
-use std::sync::Arc;
+use std::io::{self, Read};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
@@ -7,7 +8,9 @@ use uwebsockets_rs::app::App;
 use uwebsockets_rs::http_request::HttpRequest;
 use uwebsockets_rs::http_response::HttpResponse;
 use uwebsockets_rs::listen_socket::ListenSocket;
+use uwebsockets_rs::sse::Event;
 use uwebsockets_rs::us_socket_context_options::UsSocketContextOptions;
+use uwebsockets_rs::uws_loop::{get_loop, set_interval, set_timeout};
 
 fn main() {
     let config = UsSocketContextOptions {
@@ -38,6 +41,9 @@ fn main() {
         })
         .post("/long", body)
         .get("/async", async_http_handler)
+        .get("/timer", timer_http_handler)
+        .get("/stream", stream_http_handler)
+        .get("/events", events_http_handler)
         .listen(3001, None::<fn(ListenSocket)>)
         .run();
 }
@@ -91,3 +97,43 @@ fn async_http_handler(mut res: HttpResponse, _: HttpRequest) {
         }
     });
 }
+
+fn timer_http_handler(mut res: HttpResponse, _: HttpRequest) {
+    let aborted = Arc::new(AtomicBool::new(false));
+    let aborted_to_move = aborted.clone();
+
+    res.on_aborted(move || aborted_to_move.store(true, Ordering::Relaxed));
+
+    set_timeout(get_loop(), Duration::from_secs(1), move || {
+        if !aborted.load(Ordering::Relaxed) {
+            res.end(Some("result".as_bytes()), true);
+        }
+    });
+}
+
+fn stream_http_handler(res: HttpResponse, _: HttpRequest) {
+    // 64 MiB of zeroes, sent without ever holding more than a chunk in memory
+    let total_len = 64 * 1024 * 1024;
+    res.write_header("Content-Type", "application/octet-stream");
+    res.stream(total_len, io::repeat(0).take(total_len));
+}
+
+fn events_http_handler(res: HttpResponse, req: HttpRequest) {
+    let events = res.event_stream(&req);
+    events.keep_alive(Duration::from_secs(15));
+
+    let mut counter: u64 = events
+        .last_event_id()
+        .and_then(|id| id.parse().ok())
+        .unwrap_or(0);
+    let ticking_events = events.clone();
+    let ticker = set_interval(get_loop(), Duration::from_secs(1), move || {
+        counter += 1;
+        ticking_events.send(&Event::new(format!("tick {counter}")).id(counter.to_string()));
+    });
+
+    events.on_close(move || {
+        println!("Event stream closed");
+        ticker.cancel();
+    });
+}
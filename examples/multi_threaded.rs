@@ -0,0 +1,38 @@
+use std::thread;
+use std::time::Duration;
+
+use uwebsockets_rs::http_response::HttpResponse;
+use uwebsockets_rs::multi_threaded_app::MultiThreadedApp;
+use uwebsockets_rs::us_socket_context_options::UsSocketContextOptions;
+
+fn main() {
+    let config = UsSocketContextOptions {
+        key_file_name: None,
+        cert_file_name: None,
+        passphrase: None,
+        dh_params_file_name: None,
+        ca_file_name: None,
+        ssl_ciphers: None,
+        ssl_prefer_low_memory_usage: None,
+    };
+
+    let threads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+
+    let app = MultiThreadedApp::new(threads, 3001, config, |app| {
+        app.get("/*", |res: HttpResponse, _| {
+            let thread = thread::current();
+            let name = thread.name().unwrap_or("unknown");
+            res.end(Some(format!("Hello from {name}").as_bytes()), false);
+        });
+    })
+    .expect("Can't listen on port 3001");
+
+    println!("Listening on 3001 with {} threads", app.threads());
+
+    thread::sleep(Duration::from_secs(60));
+    println!("Shutting down");
+    app.shutdown();
+    app.join().expect("Worker thread panicked");
+}
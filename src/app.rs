@@ -1,29 +1,77 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int};
+use std::io;
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::ptr::null_mut;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use std::{
     ffi::{c_void, CString},
     pin::Pin,
 };
-use std::ffi::{c_char, c_int};
-use std::ptr::null_mut;
 
 use libuwebsockets_sys::{
-    us_listen_socket_t, uws_app_any, uws_app_close, uws_app_connect, uws_app_delete, uws_app_get,
-    uws_app_listen, uws_app_listen_config_t, uws_app_options, uws_app_patch, uws_app_post,
-    uws_app_put, uws_app_run, uws_app_t, uws_app_trace, uws_create_app, uws_method_handler,
-    uws_req_t, uws_res_t, uws_ws,
+    us_listen_socket_t, uws_app_any, uws_app_connect, uws_app_delete, uws_app_get, uws_app_listen,
+    uws_app_listen_config_t, uws_app_options, uws_app_patch, uws_app_post, uws_app_put,
+    uws_app_run, uws_app_t, uws_app_trace, uws_create_app, uws_method_handler, uws_num_subscribers,
+    uws_publish, uws_req_t, uws_res_t, uws_ws,
 };
 
+use crate::app_close::app_close;
+use crate::connection_limits::{forget_stats, ConnectionStats};
 use crate::http_request::HttpRequest;
 use crate::http_response::HttpResponseStruct;
-use crate::listen_socket::ListenSocket;
+use crate::listen_socket::{ListenOptions, ListenSocket};
+use crate::proxy_protocol::expect_headers;
+use crate::request_timeout::forget_header_timeout;
+use crate::static_files::StaticDir;
 use crate::us_socket_context_options::{UsSocketContextOptions, UsSocketContextOptionsCRepr};
+use crate::websocket::{forget_upgraded_addresses, Opcode};
 use crate::websocket_behavior::WebSocketBehavior;
 
-type RoutesData<const SSL: bool> = Vec<Pin<Box<Box<dyn Fn(HttpResponseStruct<SSL>, HttpRequest)>>>>;
+type HttpHandler<const SSL: bool> = Box<dyn Fn(HttpResponseStruct<SSL>, HttpRequest)>;
+type RoutesData<const SSL: bool> = Vec<Pin<Box<Route<SSL>>>>;
+
+/// Runs before the handler of every route and WebSocket upgrade, see `Application::before_request`.
+pub type RequestHook<const SSL: bool> =
+    Box<dyn Fn(&HttpResponseStruct<SSL>, &HttpRequest) -> ControlFlow<()>>;
+type RequestHooks<const SSL: bool> = Rc<RefCell<Vec<RequestHook<SSL>>>>;
+
+struct Route<const SSL: bool> {
+    handler: HttpHandler<SSL>,
+    hooks: RequestHooks<SSL>,
+}
+
+impl<const SSL: bool> Route<SSL> {
+    fn dispatch(&self, res: HttpResponseStruct<SSL>, req: HttpRequest) {
+        if run_request_hooks(&self.hooks, &res, &req).is_break() {
+            return;
+        }
+        (self.handler)(res, req);
+    }
+}
+
+fn run_request_hooks<const SSL: bool>(
+    hooks: &RequestHooks<SSL>,
+    res: &HttpResponseStruct<SSL>,
+    req: &HttpRequest,
+) -> ControlFlow<()> {
+    res.clear_request_state();
+    for hook in hooks.borrow().iter() {
+        hook(res, req)?;
+    }
+    ControlFlow::Continue(())
+}
 
 pub struct Application<const SSL: bool> {
     routes_data: RoutesData<SSL>,
+    request_hooks: RequestHooks<SSL>,
+    pub(crate) connection_stats: ConnectionStats,
+    pub(crate) liveness: AppLiveness,
     _socket_context_options: UsSocketContextOptionsCRepr,
-    native: NativeApp,
+    pub(crate) native: NativeApp,
 }
 
 impl<const SSL: bool> Application<SSL> {
@@ -31,19 +79,64 @@ impl<const SSL: bool> Application<SSL> {
         let socket_context_options: UsSocketContextOptionsCRepr = socket_config.into();
         let native_config = socket_context_options.to_ffi();
 
-        unsafe {
-            Self {
-                routes_data: Vec::new(),
-                _socket_context_options: socket_context_options,
-                native: NativeApp {
-                    app_ptr: uws_create_app(SSL as i32, native_config),
-                },
-            }
+        let app_ptr = unsafe { uws_create_app(SSL as i32, native_config) };
+        let connection_stats = ConnectionStats::default();
+        connection_stats.install::<SSL>(app_ptr);
+        let liveness = AppLiveness::default();
+        LIVENESS.with(|all| all.borrow_mut().insert(app_ptr as usize, liveness.clone()));
+
+        Self {
+            routes_data: Vec::new(),
+            request_hooks: Rc::new(RefCell::new(Vec::new())),
+            connection_stats,
+            liveness,
+            _socket_context_options: socket_context_options,
+            native: NativeApp { app_ptr },
         }
     }
 
-    pub fn ws(&mut self, pattern: &str, websocket_behavior: WebSocketBehavior<SSL>) -> &mut Self {
+    pub fn ws(
+        &mut self,
+        pattern: &str,
+        mut websocket_behavior: WebSocketBehavior<SSL>,
+    ) -> &mut Self {
         let pattern_c = CString::new(pattern).expect("key_file_name contains 0 byte");
+        // Upgrades always go through Rust so request hooks also see them
+        let hooks = self.request_hooks.clone();
+        let route = Rc::new(self.connection_stats.websocket_route(pattern));
+        let upgrade = websocket_behavior.upgrade.take();
+        let upgrade_route = route.clone();
+        websocket_behavior.upgrade = Some(Box::new(move |res, req, context| {
+            if run_request_hooks(&hooks, &res, &req).is_break() {
+                return;
+            }
+            if upgrade_route.admit(&res).is_break() {
+                return;
+            }
+            match &upgrade {
+                Some(upgrade) => upgrade(res, req, context),
+                None => HttpResponseStruct::default_upgrade(res, req, context),
+            }
+        }));
+        // Carries the client addresses known to the upgrade request over to the WebSocket
+        let open = websocket_behavior.open.take();
+        let open_route = route.clone();
+        websocket_behavior.open = Some(Box::new(move |ws| {
+            open_route.opened();
+            ws.adopt_upgraded_addresses();
+            if let Some(open) = &open {
+                open(ws);
+            }
+        }));
+        let close = websocket_behavior.close.take();
+        websocket_behavior.close = Some(Box::new(move |ws, code, message| {
+            route.closed();
+            let native = ws.native;
+            if let Some(close) = &close {
+                close(ws, code, message);
+            }
+            forget_upgraded_addresses(native);
+        }));
         let (behavior, user_callbacks) = websocket_behavior.into();
         let user_callbacks = Box::into_raw(Box::new(user_callbacks));
         unsafe {
@@ -76,11 +169,14 @@ impl<const SSL: bool> Application<SSL> {
         let pattern_c = CString::new(pattern).expect("key_file_name contains 0 byte");
 
         unsafe {
-            self.routes_data.push(Box::pin(Box::new(handler)));
-            let handler = self.routes_data.last().unwrap();
+            self.routes_data.push(Box::pin(Route {
+                handler: Box::new(handler),
+                hooks: self.request_hooks.clone(),
+            }));
+            let route = self.routes_data.last().unwrap();
 
-            let user_data = Pin::as_ref(handler).get_ref();
-            let user_data_ptr: *const Box<dyn Fn(HttpResponseStruct<SSL>, HttpRequest)> = user_data;
+            let user_data = Pin::as_ref(route).get_ref();
+            let user_data_ptr: *const Route<SSL> = user_data;
 
             let handler = if SSL { ssl_http_handler } else { http_handler };
             registrar(
@@ -157,12 +253,56 @@ impl<const SSL: bool> Application<SSL> {
         self.register_http_handler(pattern, handler, uws_app_any)
     }
 
+    /// Serves files below `root` on `GET {prefix}/*`.
+    ///
+    /// Directories are served through their `index.html`, paths escaping `root` get a 403.
+    /// Supports conditional requests (`If-None-Match`, `If-Modified-Since`) and single byte ranges.
+    /// Fails if `root` can't be resolved.
+    pub fn serve_dir(&mut self, prefix: &str, root: impl Into<PathBuf>) -> io::Result<&mut Self> {
+        let static_dir = StaticDir::new(prefix, root.into())?;
+        let pattern = static_dir.pattern();
+        Ok(self.get(&pattern, move |res, req| static_dir.serve(res, req)))
+    }
+
+    /// Adds a hook that runs before the handler of every route and WebSocket upgrade, also
+    /// of those registered earlier. Hooks run in the order they were added, returning
+    /// `ControlFlow::Break` means the hook answered the request and skips the rest.
+    ///
+    /// Headers added with `HttpResponseStruct::defer_header` end up in whatever response the
+    /// handler sends.
+    pub fn before_request(
+        &mut self,
+        hook: impl Fn(&HttpResponseStruct<SSL>, &HttpRequest) -> ControlFlow<()> + 'static,
+    ) -> &mut Self {
+        self.request_hooks.borrow_mut().push(Box::new(hook));
+        self
+    }
+
+    // For built-in hooks that must run before those of the user
+    pub(crate) fn before_request_first(
+        &mut self,
+        hook: impl Fn(&HttpResponseStruct<SSL>, &HttpRequest) -> ControlFlow<()> + 'static,
+    ) -> &mut Self {
+        self.request_hooks.borrow_mut().insert(0, Box::new(hook));
+        self
+    }
+
     pub fn run(&mut self) {
         unsafe { uws_app_run(SSL as i32, self.native.app_ptr) }
     }
 
     pub fn close(&self) {
-        unsafe { uws_app_close(SSL as i32, self.native.app_ptr) }
+        app_close::<SSL>(self.native)
+    }
+
+    pub fn publish(&self, topic: &str, message: &[u8], opcode: Opcode, compress: bool) -> bool {
+        unsafe { app_publish(SSL, self.native, topic, message, opcode, compress) }
+    }
+
+    pub fn num_subscribers(&self, topic: &str) -> u32 {
+        let topic_ptr = topic.as_ptr() as *const c_char;
+        let topic_len = topic.len();
+        unsafe { uws_num_subscribers(SSL as c_int, self.native.app_ptr, topic_ptr, topic_len) }
     }
 
     pub fn listen(
@@ -192,6 +332,106 @@ impl<const SSL: bool> Application<SSL> {
         }
         self
     }
+
+    pub fn listen_with_options(
+        &mut self,
+        port: i32,
+        options: ListenOptions,
+        handler: Option<impl FnOnce(ListenSocket) + 'static + Unpin>,
+    ) -> &mut Self {
+        let app = self.native.app_ptr;
+        self.listen(
+            port,
+            Some(move |listen_socket: ListenSocket| {
+                if options.proxy_protocol && !listen_socket.listen_socket_ptr.is_null() {
+                    expect_headers::<SSL>(app, listen_socket);
+                }
+                if let Some(handler) = handler {
+                    handler(listen_socket);
+                }
+            }),
+        )
+    }
+}
+
+impl<const SSL: bool> Drop for Application<SSL> {
+    fn drop(&mut self) {
+        forget_stats(self.native.app_ptr);
+        forget_header_timeout(self.native.app_ptr);
+        end_liveness(self.native.app_ptr);
+    }
+}
+
+/// Tells other threads whether an app can still be reached through the loop of its thread,
+/// that loop may be freed once the app is closed or dropped.
+#[derive(Clone)]
+pub(crate) struct AppLiveness {
+    alive: Arc<Mutex<bool>>,
+}
+
+impl Default for AppLiveness {
+    fn default() -> Self {
+        AppLiveness {
+            alive: Arc::new(Mutex::new(true)),
+        }
+    }
+}
+
+impl AppLiveness {
+    fn lock(&self) -> MutexGuard<'_, bool> {
+        self.alive.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn is_alive(&self) -> bool {
+        *self.lock()
+    }
+
+    /// Runs `f` if the app is alive, it can't end before `f` returns. Meant for handing work
+    /// to the loop of the app with `loop_defer`.
+    pub(crate) fn while_alive(&self, f: impl FnOnce()) -> bool {
+        let alive = self.lock();
+        if *alive {
+            f();
+        }
+        *alive
+    }
+}
+
+thread_local! {
+    // By native app, for closing through `app_close` which only has the native app
+    static LIVENESS: RefCell<HashMap<usize, AppLiveness>> = RefCell::new(HashMap::new());
+}
+
+pub(crate) fn end_liveness(app: *mut uws_app_t) {
+    // Apps kept in thread locals may be dropped after the map
+    let liveness = LIVENESS.try_with(|all| all.borrow_mut().remove(&(app as usize)));
+    if let Ok(Some(liveness)) = liveness {
+        *liveness.lock() = false;
+    }
+}
+
+pub(crate) unsafe fn app_publish(
+    ssl: bool,
+    native: NativeApp,
+    topic: &str,
+    message: &[u8],
+    opcode: Opcode,
+    compress: bool,
+) -> bool {
+    let topic_ptr = topic.as_ptr() as *const c_char;
+    let topic_len = topic.len();
+    let message_ptr = message.as_ptr() as *const c_char;
+    let message_len = message.len();
+    uws_publish(
+        ssl as c_int,
+        native.app_ptr,
+        topic_ptr,
+        topic_len,
+        message_ptr,
+        message_len,
+        opcode.into(),
+        compress,
+    )
 }
 
 unsafe extern "C" fn http_handler(
@@ -202,9 +442,9 @@ unsafe extern "C" fn http_handler(
     let req = HttpRequest::new(request);
     let response = HttpResponseStruct::<false>::new(response);
 
-    let user_handler = user_data as *mut Box<dyn Fn(HttpResponseStruct<false>, HttpRequest)>;
-    let user_handler = user_handler.as_ref().unwrap();
-    user_handler(response, req);
+    let route = user_data as *const Route<false>;
+    let route = route.as_ref().unwrap();
+    route.dispatch(response, req);
 }
 
 unsafe extern "C" fn ssl_http_handler(
@@ -214,9 +454,9 @@ unsafe extern "C" fn ssl_http_handler(
 ) {
     let req = HttpRequest::new(request);
     let response = HttpResponseStruct::<true>::new(response);
-    let user_handler = user_data as *mut Box<dyn Fn(HttpResponseStruct<true>, HttpRequest)>;
-    let user_handler = user_handler.as_ref().unwrap();
-    user_handler(response, req);
+    let route = user_data as *const Route<true>;
+    let route = route.as_ref().unwrap();
+    route.dispatch(response, req);
 }
 
 unsafe extern "C" fn on_listen(
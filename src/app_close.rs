@@ -1,7 +1,14 @@
 use libuwebsockets_sys::uws_app_close;
 
-use crate::app::NativeApp;
+use crate::app::{end_liveness, NativeApp};
+use crate::connection_limits::forget_stats;
+use crate::proxy_protocol::forget_app;
+use crate::request_timeout::forget_header_timeout;
 
 pub fn app_close<const SSL: bool>(app: NativeApp) {
     unsafe { uws_app_close(SSL as i32, app.app_ptr) }
+    forget_app(app.app_ptr);
+    forget_stats(app.app_ptr);
+    forget_header_timeout(app.app_ptr);
+    end_liveness(app.app_ptr);
 }
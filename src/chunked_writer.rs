@@ -0,0 +1,235 @@
+use std::cell::{Cell, RefCell};
+use std::ffi::{c_int, c_void};
+use std::io::{self, Write};
+use std::ptr::null;
+use std::rc::Rc;
+
+use libuwebsockets_sys::{uws_res_on_writable, uws_res_t};
+
+use crate::http_response::HttpResponseStruct;
+use crate::request_timeout::set_on_aborted;
+
+type OnDrainHandler = Box<dyn FnMut()>;
+type OnAbortedHandler = Box<dyn FnOnce()>;
+
+pub(crate) const DEFAULT_MAX_BUFFERED: usize = 1024 * 1024;
+
+struct ChunkedState<const SSL: bool> {
+    res: HttpResponseStruct<SSL>,
+    buffer: RefCell<Vec<u8>>,
+    max_buffered: Cell<usize>,
+    backpressured: Cell<bool>,
+    finishing: Cell<bool>,
+    done: Cell<bool>,
+    aborted: Cell<bool>,
+    on_drain: RefCell<Option<OnDrainHandler>>,
+    on_aborted: RefCell<Option<OnAbortedHandler>>,
+    // The reference owned by the uWS callbacks, null once it was given back
+    registration: Cell<*const ChunkedState<SSL>>,
+}
+
+/// Writes a response body with chunked transfer encoding through `HttpResponseStruct::write`.
+///
+/// Once uWS reports backpressure, further writes are kept in memory and flushed from
+/// `on_writable`, so `write` never blocks. Past `max_buffered` bytes writes fail with
+/// `WouldBlock` instead, `on_drain` tells the producer when the socket caught up again.
+/// Takes over `on_writable` and `on_aborted` of the response.
+///
+/// Trailers can't be sent. uWS frames every `write` as a chunk and writes the last chunk
+/// itself in `end`, the C API has no way to put anything after it. Sending trailers would
+/// mean framing the body behind uWS's back, which depends on its internals.
+pub struct ChunkedWriter<const SSL: bool> {
+    state: Rc<ChunkedState<SSL>>,
+}
+
+impl<const SSL: bool> ChunkedWriter<SSL> {
+    pub fn new(res: &HttpResponseStruct<SSL>) -> Self {
+        let native = res.native;
+        let state = Rc::new(ChunkedState {
+            res: HttpResponseStruct::new(native),
+            buffer: RefCell::new(Vec::new()),
+            max_buffered: Cell::new(DEFAULT_MAX_BUFFERED),
+            backpressured: Cell::new(false),
+            finishing: Cell::new(false),
+            done: Cell::new(false),
+            aborted: Cell::new(false),
+            on_drain: RefCell::new(None),
+            on_aborted: RefCell::new(None),
+            registration: Cell::new(null()),
+        });
+
+        let registration = Rc::into_raw(state.clone());
+        state.registration.set(registration);
+        unsafe {
+            uws_res_on_writable(
+                SSL as c_int,
+                native,
+                Some(chunked_on_writable::<SSL>),
+                registration as *mut c_void,
+            );
+            set_on_aborted::<SSL>(
+                native,
+                chunked_on_aborted::<SSL>,
+                registration as *mut c_void,
+            );
+        }
+
+        ChunkedWriter { state }
+    }
+
+    /// Called every time the buffered data went out and more can be written without buffering.
+    pub fn on_drain(&mut self, handler: impl FnMut() + 'static) {
+        *self.state.on_drain.borrow_mut() = Some(Box::new(handler));
+    }
+
+    /// Called once if the client goes away before the response was ended.
+    pub fn on_aborted(&mut self, handler: impl FnOnce() + 'static) {
+        *self.state.on_aborted.borrow_mut() = Some(Box::new(handler));
+    }
+
+    pub fn is_backpressured(&self) -> bool {
+        self.state.backpressured.get()
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.state.aborted.get()
+    }
+
+    /// Caps the bytes kept in memory while the client doesn't read, 1 MiB by default.
+    pub fn max_buffered(&mut self, bytes: usize) {
+        self.state.max_buffered.set(bytes);
+    }
+
+    /// Bytes kept in memory until the socket becomes writable.
+    pub fn buffered_len(&self) -> usize {
+        self.state.buffer.borrow().len()
+    }
+
+    /// Ends the response once everything buffered so far is written.
+    /// Dropping the writer does the same.
+    pub fn finish(self) {
+        self.state.finish();
+    }
+}
+
+impl<const SSL: bool> Write for ChunkedWriter<SSL> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let state = &self.state;
+        if state.aborted.get() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Response was aborted",
+            ));
+        }
+
+        if state.backpressured.get() {
+            let mut buffer = state.buffer.borrow_mut();
+            let room = state.max_buffered.get().saturating_sub(buffer.len());
+            if room == 0 && !buf.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let taken = buf.len().min(room);
+            buffer.extend_from_slice(&buf[..taken]);
+            return Ok(taken);
+        }
+        if !state.res.write(buf) {
+            // uWS keeps what it could not send, only later writes need buffering
+            state.backpressured.set(true);
+        }
+        Ok(buf.len())
+    }
+
+    // Buffered data can only be flushed by the loop once the socket is writable
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<const SSL: bool> Drop for ChunkedWriter<SSL> {
+    fn drop(&mut self) {
+        self.state.finish();
+    }
+}
+
+impl<const SSL: bool> ChunkedState<SSL> {
+    fn finish(&self) {
+        if self.done.get() || self.finishing.replace(true) {
+            return;
+        }
+        if !self.backpressured.get() {
+            self.complete();
+        }
+    }
+
+    fn on_writable(&self) -> bool {
+        if self.done.get() {
+            return true;
+        }
+
+        let buffer = self.buffer.take();
+        if !buffer.is_empty() && !self.res.write(&buffer) {
+            return false;
+        }
+        self.backpressured.set(false);
+
+        if self.finishing.get() {
+            self.complete();
+            return true;
+        }
+
+        let handler = self.on_drain.borrow_mut().take();
+        if let Some(mut handler) = handler {
+            handler();
+            let mut on_drain = self.on_drain.borrow_mut();
+            if on_drain.is_none() {
+                *on_drain = Some(handler);
+            }
+        }
+        true
+    }
+
+    fn complete(&self) {
+        self.done.set(true);
+        self.res.end(None, false);
+        // end marks the response as done, which unregisters the uWS callbacks
+        self.release_registration();
+    }
+
+    fn release_registration(&self) {
+        let registration = self.registration.replace(null());
+        if !registration.is_null() {
+            unsafe { drop(Rc::from_raw(registration)) };
+        }
+    }
+}
+
+unsafe extern "C" fn chunked_on_writable<const SSL: bool>(
+    _: *mut uws_res_t,
+    _: u64,
+    user_data: *mut c_void,
+) -> bool {
+    let state = user_data as *const ChunkedState<SSL>;
+    // Keep the state alive while it may give back its own registration
+    Rc::increment_strong_count(state);
+    let state = Rc::from_raw(state);
+    state.on_writable()
+}
+
+unsafe extern "C" fn chunked_on_aborted<const SSL: bool>(
+    _: *mut uws_res_t,
+    user_data: *mut c_void,
+) {
+    let state = user_data as *const ChunkedState<SSL>;
+    Rc::increment_strong_count(state);
+    let state = Rc::from_raw(state);
+    state.aborted.set(true);
+    state.done.set(true);
+    state.buffer.take();
+    state.on_drain.take();
+    state.release_registration();
+
+    let handler = state.on_aborted.take();
+    if let Some(handler) = handler {
+        handler();
+    }
+}
@@ -0,0 +1,319 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::ControlFlow;
+use std::str::FromStr;
+
+use crate::app::Application;
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponseStruct;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct InvalidCidr;
+
+impl fmt::Display for InvalidCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Invalid CIDR, expected an address like 10.0.0.0/8 or fd00::/8")
+    }
+}
+
+impl std::error::Error for InvalidCidr {}
+
+/// A network like `10.0.0.0/8`, parsed with `str::parse`. A bare address is a single host.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct IpCidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    pub fn new(network: IpAddr, prefix_len: u8) -> Result<Self, InvalidCidr> {
+        let max_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return Err(InvalidCidr);
+        }
+        Ok(IpCidr {
+            network,
+            prefix_len,
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = prefix_len as usize / 8;
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    let rest_bits = prefix_len % 8;
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+impl FromStr for IpCidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix_len) = match s.split_once('/') {
+            Some((network, prefix_len)) => (
+                network,
+                Some(prefix_len.parse::<u8>().map_err(|_| InvalidCidr)?),
+            ),
+            None => (s, None),
+        };
+        let network: IpAddr = network.parse().map_err(|_| InvalidCidr)?;
+        let prefix_len = prefix_len.unwrap_or(if network.is_ipv4() { 32 } else { 128 });
+        IpCidr::new(network, prefix_len)
+    }
+}
+
+/// Where trusted proxies put the address of the hop in front of them.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ForwardedSource {
+    /// The source address of the PROXY protocol header, for listen sockets with
+    /// `ListenOptions::proxy_protocol`.
+    ProxyProtocol,
+    XForwardedFor,
+    /// The `for` parameters of the RFC 7239 `Forwarded` header.
+    Forwarded,
+}
+
+/// Finds the client address behind trusted proxies, installed with
+/// `Application::resolve_client_ip`.
+///
+/// Only the source named on creation is read, it has to be the one the trusted proxies set.
+/// Any other forwarding header comes from the client and is ignored. The source is only
+/// believed while the address it came from is trusted and is walked from the closest hop
+/// outwards, the first untrusted address is the client. If every hop is trusted the
+/// outermost one is used.
+#[derive(Debug, Clone)]
+pub struct ClientIpResolver {
+    trusted: Vec<IpCidr>,
+    source: ForwardedSource,
+}
+
+impl ClientIpResolver {
+    pub fn new(source: ForwardedSource) -> Self {
+        ClientIpResolver {
+            trusted: Vec::new(),
+            source,
+        }
+    }
+
+    pub fn trust(mut self, proxies: IpCidr) -> Self {
+        self.trusted.push(proxies);
+        self
+    }
+
+    /// Trusts the loopback and private networks.
+    pub fn trust_private_networks(mut self) -> Self {
+        for cidr in [
+            "127.0.0.0/8",
+            "10.0.0.0/8",
+            "172.16.0.0/12",
+            "192.168.0.0/16",
+            "::1",
+            "fc00::/7",
+        ] {
+            self.trusted
+                .push(cidr.parse().expect("built in CIDRs are valid"));
+        }
+        self
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    pub fn resolve<const SSL: bool>(
+        &self,
+        res: &HttpResponseStruct<SSL>,
+        req: &HttpRequest,
+    ) -> Option<IpAddr> {
+        // The header already replaced the remote address, trust is decided by the TCP peer
+        let peer = match self.source {
+            ForwardedSource::ProxyProtocol => res.peer_address()?,
+            _ => res.get_remote_address()?,
+        };
+        if !self.is_trusted(peer) {
+            return Some(peer);
+        }
+
+        let client = match self.source {
+            ForwardedSource::ProxyProtocol => res.get_remote_address().unwrap_or(peer),
+            ForwardedSource::XForwardedFor => {
+                let hops = header_values(req, "x-forwarded-for")
+                    .flat_map(|value| value.split(','))
+                    .map(parse_hop)
+                    .collect();
+                self.walk(peer, hops)
+            }
+            ForwardedSource::Forwarded => {
+                let hops = header_values(req, "forwarded")
+                    .flat_map(|value| value.split(','))
+                    .filter_map(forwarded_for)
+                    .map(parse_hop)
+                    .collect();
+                self.walk(peer, hops)
+            }
+        };
+        Some(client)
+    }
+
+    // Hops are listed outermost first, unparseable ones end the walk
+    fn walk(&self, mut client: IpAddr, hops: Vec<Option<IpAddr>>) -> IpAddr {
+        for hop in hops.into_iter().rev() {
+            let Some(hop) = hop else {
+                break;
+            };
+            client = hop;
+            if !self.is_trusted(hop) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+fn header_values<'a>(req: &HttpRequest<'a>, name: &'a str) -> impl Iterator<Item = &'a str> {
+    req.read_headers()
+        .into_iter()
+        .filter(move |(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn forwarded_for(element: &str) -> Option<&str> {
+    element.split(';').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        key.trim().eq_ignore_ascii_case("for").then(|| value.trim())
+    })
+}
+
+// Accepts `1.2.3.4`, `1.2.3.4:80`, `::1`, `[::1]` and `[::1]:80`, optionally quoted
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    if let Ok(ip) = hop.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+    if let Ok(addr) = hop.parse::<SocketAddr>() {
+        return Some(addr.ip().to_canonical());
+    }
+    hop.strip_prefix('[')?
+        .strip_suffix(']')?
+        .parse::<IpAddr>()
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
+
+impl<const SSL: bool> Application<SSL> {
+    /// Resolves the client address of every request and WebSocket upgrade, it is then
+    /// available from `client_ip` of the request, the response and the WebSocket.
+    ///
+    /// Runs as a request hook, so hooks relying on the address must be added after it.
+    pub fn resolve_client_ip(&mut self, resolver: ClientIpResolver) -> &mut Self {
+        self.before_request(move |res, req| {
+            if let Some(client_ip) = resolver.resolve(res, req) {
+                req.client_ip.set(Some(client_ip));
+                res.set_client_ip(client_ip);
+            }
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        let cidr: IpCidr = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(cidr, IpCidr::new(ip("10.0.0.0"), 8).unwrap());
+        let host: IpCidr = "2001:db8::1".parse().unwrap();
+        assert_eq!(host, IpCidr::new(ip("2001:db8::1"), 128).unwrap());
+
+        for invalid in ["10.0.0.0/33", "fd00::/129", "10.0.0/8", "10.0.0.0/"] {
+            assert_eq!(invalid.parse::<IpCidr>(), Err(InvalidCidr), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn matches_networks() {
+        let cidr: IpCidr = "172.16.0.0/12".parse().unwrap();
+        assert!(cidr.contains(ip("172.16.0.1")));
+        assert!(cidr.contains(ip("172.31.255.255")));
+        assert!(!cidr.contains(ip("172.32.0.0")));
+        assert!(!cidr.contains(ip("fd00::1")));
+        // IPv4 mapped addresses are IPv4 clients
+        assert!(cidr.contains(ip("::ffff:172.16.0.1")));
+
+        let cidr: IpCidr = "fc00::/7".parse().unwrap();
+        assert!(cidr.contains(ip("fd12::1")));
+        assert!(!cidr.contains(ip("fe80::1")));
+
+        let everything: IpCidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn parses_hops() {
+        assert_eq!(parse_hop(" 203.0.113.7 "), Some(ip("203.0.113.7")));
+        assert_eq!(parse_hop("203.0.113.7:4711"), Some(ip("203.0.113.7")));
+        assert_eq!(parse_hop("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_hop("\"[2001:db8::1]\""), Some(ip("2001:db8::1")));
+        assert_eq!(parse_hop("\"[2001:db8::1]:4711\""), Some(ip("2001:db8::1")));
+        assert_eq!(parse_hop("::ffff:203.0.113.7"), Some(ip("203.0.113.7")));
+        assert_eq!(parse_hop("unknown"), None);
+        assert_eq!(parse_hop("_hidden"), None);
+    }
+
+    #[test]
+    fn finds_forwarded_for() {
+        assert_eq!(forwarded_for("for=203.0.113.7"), Some("203.0.113.7"));
+        assert_eq!(
+            forwarded_for("proto=https; For=\"[2001:db8::1]:4711\";by=10.0.0.1"),
+            Some("\"[2001:db8::1]:4711\"")
+        );
+        assert_eq!(forwarded_for("by=10.0.0.1;proto=http"), None);
+    }
+
+    #[test]
+    fn walks_hops_until_the_first_untrusted_one() {
+        let resolver = ClientIpResolver::new(ForwardedSource::XForwardedFor)
+            .trust("10.0.0.0/8".parse().unwrap());
+        let hops = |hops: &[&str]| hops.iter().map(|hop| parse_hop(hop)).collect();
+        let peer = ip("10.0.0.1");
+
+        let client = resolver.walk(peer, hops(&["198.51.100.1", "203.0.113.7", "10.0.0.2"]));
+        assert_eq!(client, ip("203.0.113.7"));
+        // Everything trusted, the outermost hop is the client
+        assert_eq!(
+            resolver.walk(peer, hops(&["10.0.0.3", "10.0.0.2"])),
+            ip("10.0.0.3")
+        );
+        assert_eq!(resolver.walk(peer, hops(&[])), peer);
+        // Nothing beyond an unparseable hop is believed
+        let client = resolver.walk(peer, hops(&["198.51.100.1", "garbage", "10.0.0.2"]));
+        assert_eq!(client, ip("10.0.0.2"));
+    }
+}
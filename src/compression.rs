@@ -0,0 +1,312 @@
+use std::io::{self, Write};
+
+use brotli::CompressorWriter;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+
+use crate::chunked_writer::ChunkedWriter;
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponseStruct;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ContentEncoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+}
+
+pub struct HttpCompressionOptions {
+    /// Supported encodings, in order of preference when the client likes several equally.
+    pub encodings: Vec<ContentEncoding>,
+    /// Bodies passed to `end` below this size are sent as they are.
+    pub min_size: usize,
+    /// 0 to 9, brotli maps it onto its own 0 to 11 scale.
+    pub level: u32,
+    /// Content types to never compress, on top of the built-in list of compressed formats.
+    pub skip_content_types: Vec<&'static str>,
+}
+
+impl Default for HttpCompressionOptions {
+    fn default() -> Self {
+        HttpCompressionOptions {
+            encodings: vec![
+                ContentEncoding::Brotli,
+                ContentEncoding::Gzip,
+                ContentEncoding::Deflate,
+            ],
+            min_size: 1024,
+            level: 6,
+            skip_content_types: Vec::new(),
+        }
+    }
+}
+
+impl HttpCompressionOptions {
+    /// Picks the encoding with the highest `q` value in `Accept-Encoding`.
+    pub fn negotiate(&self, accept_encoding: &str) -> Option<ContentEncoding> {
+        let mut wildcard_q = None;
+        let mut listed = Vec::new();
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if coding == "*" {
+                wildcard_q = Some(q);
+            } else {
+                listed.push((coding, q));
+            }
+        }
+
+        let mut best: Option<(ContentEncoding, f32)> = None;
+        for encoding in &self.encodings {
+            let q = listed
+                .iter()
+                .find(|(coding, _)| {
+                    coding == encoding.as_str()
+                        || (*encoding == ContentEncoding::Gzip && coding == "x-gzip")
+                })
+                .map(|(_, q)| *q)
+                .or(wildcard_q)
+                .unwrap_or(0.0);
+
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((*encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    pub fn is_compressible(&self, content_type: &str) -> bool {
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+
+        if self
+            .skip_content_types
+            .iter()
+            .any(|skipped| skipped.eq_ignore_ascii_case(&mime))
+        {
+            return false;
+        }
+
+        match mime.split_once('/') {
+            Some(("image", subtype)) => subtype == "svg+xml",
+            Some(("video", _)) | Some(("audio", _)) => false,
+            Some(("font", subtype)) => subtype != "woff" && subtype != "woff2",
+            _ => !matches!(
+                mime.as_str(),
+                "application/zip"
+                    | "application/gzip"
+                    | "application/x-gzip"
+                    | "application/x-bzip2"
+                    | "application/x-xz"
+                    | "application/x-7z-compressed"
+                    | "application/x-rar-compressed"
+                    | "application/zstd"
+                    | "application/pdf"
+                    | "application/octet-stream"
+            ),
+        }
+    }
+}
+
+/// A response that gets compressed if the client supports it.
+///
+/// Created with `HttpResponseStruct::compressed`, the status and other headers must be
+/// written before ending it or starting a writer.
+pub struct CompressedResponse<'a, const SSL: bool> {
+    res: HttpResponseStruct<SSL>,
+    options: &'a HttpCompressionOptions,
+    encoding: Option<ContentEncoding>,
+}
+
+impl<'a, const SSL: bool> CompressedResponse<'a, SSL> {
+    pub fn new(
+        res: &HttpResponseStruct<SSL>,
+        req: &HttpRequest,
+        options: &'a HttpCompressionOptions,
+    ) -> Self {
+        let encoding = req
+            .get_header("accept-encoding")
+            .and_then(|accept_encoding| options.negotiate(accept_encoding));
+        CompressedResponse {
+            res: HttpResponseStruct::new(res.native),
+            options,
+            encoding,
+        }
+    }
+
+    pub fn encoding(&self) -> Option<ContentEncoding> {
+        self.encoding
+    }
+
+    pub fn end(self, content_type: &str, data: &[u8], close_connection: bool) {
+        self.res.write_header("Content-Type", content_type);
+        let encoding = match self.start(content_type) {
+            Some(encoding) if data.len() >= self.options.min_size => encoding,
+            _ => return self.res.end(Some(data), close_connection),
+        };
+
+        let compressed = match compress(encoding, self.options.level, data) {
+            Ok(compressed) if compressed.len() < data.len() => compressed,
+            _ => return self.res.end(Some(data), close_connection),
+        };
+        self.res.write_header("Content-Encoding", encoding.as_str());
+        self.res.end(Some(&compressed), close_connection);
+    }
+
+    /// Streams a body of unknown length, compressing it on the fly.
+    pub fn writer(self, content_type: &str) -> CompressingWriter<SSL> {
+        self.res.write_header("Content-Type", content_type);
+        let encoding = self.start(content_type);
+        if let Some(encoding) = encoding {
+            self.res.write_header("Content-Encoding", encoding.as_str());
+        }
+
+        let writer = ChunkedWriter::new(&self.res);
+        let level = self.options.level.min(9);
+        let encoder = match encoding {
+            None => Encoder::Identity(writer),
+            Some(ContentEncoding::Gzip) => {
+                Encoder::Gzip(GzEncoder::new(writer, Compression::new(level)))
+            }
+            Some(ContentEncoding::Deflate) => {
+                Encoder::Deflate(ZlibEncoder::new(writer, Compression::new(level)))
+            }
+            Some(ContentEncoding::Brotli) => Encoder::Brotli(Box::new(CompressorWriter::new(
+                writer,
+                4096,
+                brotli_quality(level),
+                22,
+            ))),
+        };
+
+        CompressingWriter {
+            encoder: Some(encoder),
+        }
+    }
+
+    // Returns the encoding to use, if any, once compression was considered for this content type
+    fn start(&self, content_type: &str) -> Option<ContentEncoding> {
+        if !self.options.is_compressible(content_type) {
+            return None;
+        }
+        // The response depends on Accept-Encoding, whether it ends up compressed or not
+        self.res.write_header("Vary", "Accept-Encoding");
+        self.encoding
+    }
+}
+
+fn brotli_quality(level: u32) -> u32 {
+    (level * 11).div_ceil(9)
+}
+
+fn compress(encoding: ContentEncoding, level: u32, data: &[u8]) -> io::Result<Vec<u8>> {
+    let level = level.min(9);
+    let out = Vec::with_capacity(data.len() / 2);
+    match encoding {
+        ContentEncoding::Gzip => {
+            let mut encoder = GzEncoder::new(out, Compression::new(level));
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        ContentEncoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(out, Compression::new(level));
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        ContentEncoding::Brotli => {
+            let mut encoder = CompressorWriter::new(out, 4096, brotli_quality(level), 22);
+            encoder.write_all(data)?;
+            Ok(encoder.into_inner())
+        }
+    }
+}
+
+enum Encoder<const SSL: bool> {
+    Identity(ChunkedWriter<SSL>),
+    Gzip(GzEncoder<ChunkedWriter<SSL>>),
+    Deflate(ZlibEncoder<ChunkedWriter<SSL>>),
+    Brotli(Box<CompressorWriter<ChunkedWriter<SSL>>>),
+}
+
+/// Compresses everything written to it into a chunked response, see `ChunkedWriter`.
+///
+/// `flush` pushes out what was compressed so far, which costs some compression ratio
+/// but matters for long lived streams.
+pub struct CompressingWriter<const SSL: bool> {
+    encoder: Option<Encoder<SSL>>,
+}
+
+impl<const SSL: bool> CompressingWriter<SSL> {
+    fn encoder(&mut self) -> &mut dyn Write {
+        match self.encoder.as_mut().expect("Writer is already finished") {
+            Encoder::Identity(writer) => writer,
+            Encoder::Gzip(encoder) => encoder,
+            Encoder::Deflate(encoder) => encoder,
+            Encoder::Brotli(encoder) => encoder.as_mut(),
+        }
+    }
+
+    /// Writes the end of the compressed stream and ends the response.
+    /// Dropping the writer does the same.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finish_encoder()
+    }
+
+    fn finish_encoder(&mut self) -> io::Result<()> {
+        let writer = match self.encoder.take() {
+            None => return Ok(()),
+            Some(Encoder::Identity(writer)) => writer,
+            Some(Encoder::Gzip(encoder)) => encoder.finish()?,
+            Some(Encoder::Deflate(encoder)) => encoder.finish()?,
+            Some(Encoder::Brotli(encoder)) => encoder.into_inner(),
+        };
+        writer.finish();
+        Ok(())
+    }
+}
+
+impl<const SSL: bool> Write for CompressingWriter<SSL> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder().flush()
+    }
+}
+
+impl<const SSL: bool> Drop for CompressingWriter<SSL> {
+    fn drop(&mut self) {
+        let _ = self.finish_encoder();
+    }
+}
+
+impl<const SSL: bool> HttpResponseStruct<SSL> {
+    /// Negotiates response compression with the client, see `CompressedResponse`.
+    pub fn compressed<'a>(
+        &self,
+        req: &HttpRequest,
+        options: &'a HttpCompressionOptions,
+    ) -> CompressedResponse<'a, SSL> {
+        CompressedResponse::new(self, req, options)
+    }
+}
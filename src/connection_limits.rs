@@ -0,0 +1,250 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::{c_int, c_void};
+use std::ops::ControlFlow;
+use std::ptr::null_mut;
+use std::rc::Rc;
+
+use libuwebsockets_sys::{us_socket_close, us_socket_t, uws_app_t, uws_filter, uws_res_t};
+
+use crate::app::Application;
+use crate::http_response::HttpResponseStruct;
+use crate::status_code::StatusCode;
+
+/// Caps installed with `Application::limit_connections`, nothing is capped by default.
+///
+/// Request headers are bounded by uWS itself: requests whose headers don't fit its buffer are
+/// answered with 431 and the connection is closed. The buffer takes 4 KiB unless the
+/// `UWS_HTTP_MAX_HEADERS_SIZE` environment variable holds another size in bytes, uWS reads it
+/// once, when the process starts.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionLimits {
+    max_http_connections: Option<usize>,
+    max_websockets: HashMap<String, usize>,
+}
+
+impl ConnectionLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connections beyond this are closed right after they are accepted, before they can
+    /// send a request. Upgraded WebSockets don't count.
+    pub fn max_http_connections(mut self, max: usize) -> Self {
+        self.max_http_connections = Some(max);
+        self
+    }
+
+    /// Upgrades to the WebSocket route registered with `pattern` are answered with 503 while
+    /// it has this many open connections, upgrades that are still being handled included.
+    pub fn max_websockets(mut self, pattern: impl Into<String>, max: usize) -> Self {
+        self.max_websockets.insert(pattern.into(), max);
+        self
+    }
+}
+
+#[derive(Default)]
+struct WebSocketCounter {
+    open: Cell<usize>,
+    // Admitted upgrades that were neither completed nor turned down yet
+    upgrading: Cell<usize>,
+    rejected: Cell<u64>,
+}
+
+#[derive(Default)]
+struct StatsState {
+    limits: RefCell<ConnectionLimits>,
+    http_connections: Cell<usize>,
+    rejected_http_connections: Cell<u64>,
+    websockets: RefCell<HashMap<String, Rc<WebSocketCounter>>>,
+}
+
+/// Live connection counters of an application, see `Application::connection_stats`.
+///
+/// Counting happens whether limits are installed or not.
+#[derive(Clone, Default)]
+pub struct ConnectionStats {
+    state: Rc<StatsState>,
+}
+
+impl ConnectionStats {
+    pub fn http_connections(&self) -> usize {
+        self.state.http_connections.get()
+    }
+
+    pub fn rejected_http_connections(&self) -> u64 {
+        self.state.rejected_http_connections.get()
+    }
+
+    /// Open connections of the WebSocket route registered with `pattern`.
+    pub fn websocket_connections(&self, pattern: &str) -> usize {
+        self.websocket_counter(pattern, |counter| counter.open.get())
+    }
+
+    pub fn rejected_websockets(&self, pattern: &str) -> u64 {
+        self.websocket_counter(pattern, |counter| counter.rejected.get())
+    }
+
+    fn websocket_counter<T: Default>(
+        &self,
+        pattern: &str,
+        get: impl FnOnce(&WebSocketCounter) -> T,
+    ) -> T {
+        self.state
+            .websockets
+            .borrow()
+            .get(pattern)
+            .map(|counter| get(counter))
+            .unwrap_or_default()
+    }
+
+    // Counts the connections of the app's HTTP context, uWS calls filters on open and close
+    pub(crate) fn install<const SSL: bool>(&self, app: *mut uws_app_t) {
+        STATS.with(|stats| stats.borrow_mut().insert(app as usize, self.state.clone()));
+        unsafe {
+            uws_filter(
+                SSL as c_int,
+                app,
+                Some(connection_filter::<SSL>),
+                app as *mut c_void,
+            );
+        }
+    }
+
+    /// Upgraded sockets leave the HTTP context without a close event.
+    pub(crate) fn http_connection_upgraded(&self) {
+        let open = &self.state.http_connections;
+        open.set(open.get().saturating_sub(1));
+    }
+
+    pub(crate) fn websocket_route(&self, pattern: &str) -> WebSocketRoute {
+        let counter = self
+            .state
+            .websockets
+            .borrow_mut()
+            .entry(pattern.to_string())
+            .or_default()
+            .clone();
+        WebSocketRoute {
+            stats: self.clone(),
+            pattern: pattern.to_string(),
+            counter,
+        }
+    }
+}
+
+/// Counts the connections of one WebSocket route and enforces its cap.
+pub(crate) struct WebSocketRoute {
+    stats: ConnectionStats,
+    pattern: String,
+    counter: Rc<WebSocketCounter>,
+}
+
+impl WebSocketRoute {
+    /// Answers with 503 and returns Break while the route is full. Admitted upgrades hold a
+    /// place until they complete or the response ends without them.
+    pub(crate) fn admit<const SSL: bool>(&self, res: &HttpResponseStruct<SSL>) -> ControlFlow<()> {
+        let max = self
+            .stats
+            .state
+            .limits
+            .borrow()
+            .max_websockets
+            .get(&self.pattern)
+            .copied();
+        let taken = self.counter.open.get() + self.counter.upgrading.get();
+        if max.is_some_and(|max| taken >= max) {
+            self.counter.rejected.set(self.counter.rejected.get() + 1);
+            res.write_status_code(StatusCode::SERVICE_UNAVAILABLE);
+            res.end(None, true);
+            return ControlFlow::Break(());
+        }
+
+        settle_upgrade(res.native);
+        let upgrading = &self.counter.upgrading;
+        upgrading.set(upgrading.get() + 1);
+        UPGRADING.with(|all| {
+            all.borrow_mut()
+                .insert(res.native as usize, self.counter.clone())
+        });
+        ControlFlow::Continue(())
+    }
+
+    pub(crate) fn opened(&self) {
+        self.stats.http_connection_upgraded();
+        self.counter.open.set(self.counter.open.get() + 1);
+    }
+
+    pub(crate) fn closed(&self) {
+        let open = &self.counter.open;
+        open.set(open.get().saturating_sub(1));
+    }
+}
+
+thread_local! {
+    // By native app, the filter finds nothing once the app is closed or dropped
+    static STATS: RefCell<HashMap<usize, Rc<StatsState>>> = RefCell::new(HashMap::new());
+    // Admitted upgrades by native response
+    static UPGRADING: RefCell<HashMap<usize, Rc<WebSocketCounter>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Gives back the place of an admitted upgrade once it was answered, upgraded or aborted.
+pub(crate) fn settle_upgrade(native: *mut uws_res_t) {
+    let counter = UPGRADING.with(|all| {
+        let mut all = all.borrow_mut();
+        if all.is_empty() {
+            return None;
+        }
+        all.remove(&(native as usize))
+    });
+    if let Some(counter) = counter {
+        let upgrading = &counter.upgrading;
+        upgrading.set(upgrading.get().saturating_sub(1));
+    }
+}
+
+pub(crate) fn forget_stats(app: *mut uws_app_t) {
+    // Apps kept in thread locals may be dropped after the map
+    let _ = STATS.try_with(|stats| stats.borrow_mut().remove(&(app as usize)));
+}
+
+impl<const SSL: bool> Application<SSL> {
+    /// Replaces the limits installed before.
+    pub fn limit_connections(&mut self, limits: ConnectionLimits) -> &mut Self {
+        *self.connection_stats.state.limits.borrow_mut() = limits;
+        self
+    }
+
+    pub fn connection_stats(&self) -> ConnectionStats {
+        self.connection_stats.clone()
+    }
+}
+
+unsafe extern "C" fn connection_filter<const SSL: bool>(
+    res: *mut uws_res_t,
+    event: c_int,
+    user_data: *mut c_void,
+) {
+    if event < 0 {
+        settle_upgrade(res);
+    }
+    let Some(state) = STATS.with(|stats| stats.borrow().get(&(user_data as usize)).cloned()) else {
+        return;
+    };
+    let open = &state.http_connections;
+    if event < 0 {
+        open.set(open.get().saturating_sub(1));
+        return;
+    }
+
+    open.set(open.get() + 1);
+    let max = state.limits.borrow().max_http_connections;
+    if max.is_some_and(|max| open.get() > max) {
+        state
+            .rejected_http_connections
+            .set(state.rejected_http_connections.get() + 1);
+        // Closing calls the filter again, which takes the connection off the count
+        us_socket_close(SSL as c_int, res as *mut us_socket_t, 0, null_mut());
+    }
+}
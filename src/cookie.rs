@@ -0,0 +1,391 @@
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponseStruct;
+use crate::utils::format_http_date;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Browsers only accept it together with `Secure`, which is then added automatically.
+    None,
+}
+
+impl SameSite {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CookieError {
+    InvalidName,
+    InvalidValue,
+    InvalidAttribute,
+}
+
+impl fmt::Display for CookieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            CookieError::InvalidName => "Cookie name is not a valid token",
+            CookieError::InvalidValue => "Cookie value contains forbidden characters",
+            CookieError::InvalidAttribute => "Cookie path or domain contains forbidden characters",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for CookieError {}
+
+/// A `Set-Cookie` header, written with `HttpResponseStruct::set_cookie`.
+#[derive(Debug, Clone)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    expires: Option<SystemTime>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Cookie {
+            name: name.into(),
+            value: value.into(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie that makes the browser delete `name`. Path and domain must match the original.
+    pub fn removal(name: impl Into<String>) -> Self {
+        Cookie::new(name, "")
+            .max_age(Duration::ZERO)
+            .expires(SystemTime::UNIX_EPOCH)
+    }
+
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Formats the `Set-Cookie` header value.
+    pub fn to_header_value(&self) -> Result<String, CookieError> {
+        if self.name.is_empty() || !self.name.bytes().all(is_token_byte) {
+            return Err(CookieError::InvalidName);
+        }
+        if !self.value.bytes().all(is_cookie_value_byte) {
+            return Err(CookieError::InvalidValue);
+        }
+        let attributes = [self.path.as_deref(), self.domain.as_deref()];
+        if attributes.iter().flatten().any(|attribute| {
+            attribute
+                .bytes()
+                .any(|byte| byte == b';' || byte.is_ascii_control())
+        }) {
+            return Err(CookieError::InvalidAttribute);
+        }
+
+        let mut header = format!("{}={}", self.name, self.value);
+        if let Some(path) = &self.path {
+            header.push_str("; Path=");
+            header.push_str(path);
+        }
+        if let Some(domain) = &self.domain {
+            header.push_str("; Domain=");
+            header.push_str(domain);
+        }
+        if let Some(max_age) = self.max_age {
+            header.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+        }
+        if let Some(expires) = self.expires {
+            header.push_str("; Expires=");
+            header.push_str(&format_http_date(expires));
+        }
+        if self.secure || self.same_site == Some(SameSite::None) {
+            header.push_str("; Secure");
+        }
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+        if let Some(same_site) = self.same_site {
+            header.push_str("; SameSite=");
+            header.push_str(same_site.as_str());
+        }
+        Ok(header)
+    }
+}
+
+// RFC 6265 cookie-name is an RFC 2616 token
+fn is_token_byte(byte: u8) -> bool {
+    byte.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&byte)
+}
+
+// RFC 6265 cookie-octet
+fn is_cookie_value_byte(byte: u8) -> bool {
+    byte.is_ascii_graphic() && !b"\",;\\".contains(&byte)
+}
+
+/// Parses a `Cookie` request header, pairs without `=` are skipped.
+pub fn parse_cookie_header(header: &str) -> Vec<(&str, &str)> {
+    header
+        .split(';')
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            Some((name.trim(), value))
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+impl<'a> HttpRequest<'a> {
+    pub fn get_cookies(&self) -> Vec<(&'a str, &'a str)> {
+        self.get_header("cookie")
+            .map(parse_cookie_header)
+            .unwrap_or_default()
+    }
+
+    /// The first cookie called `name`, browsers send the most specific path first.
+    pub fn get_cookie(&self, name: &str) -> Option<&'a str> {
+        self.get_cookies()
+            .into_iter()
+            .find(|(cookie_name, _)| *cookie_name == name)
+            .map(|(_, value)| value)
+    }
+}
+
+impl<const SSL: bool> HttpResponseStruct<SSL> {
+    /// Writes a `Set-Cookie` header, nothing is written if the cookie is invalid.
+    pub fn set_cookie(&self, cookie: &Cookie) -> Result<(), CookieError> {
+        let header = cookie.to_header_value()?;
+        // Already checked by `to_header_value`
+        self.try_write_header("Set-Cookie", &header)
+            .map_err(|_| CookieError::InvalidValue)
+    }
+}
+
+#[cfg(feature = "signed-cookies")]
+pub use signed::CookieKey;
+
+#[cfg(feature = "signed-cookies")]
+mod signed {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::Cookie;
+    use crate::http_request::HttpRequest;
+
+    type HmacSha256 = Hmac<Sha256>;
+
+    /// Signs cookie values with HMAC-SHA256, so clients can read but not forge them.
+    ///
+    /// The signed value is `{value}.{hex signature}`, the signature also covers the cookie
+    /// name so a value can't be moved to another cookie.
+    #[derive(Clone)]
+    pub struct CookieKey {
+        mac: HmacSha256,
+    }
+
+    impl CookieKey {
+        /// The secret should be at least 32 random bytes.
+        pub fn new(secret: &[u8]) -> Self {
+            CookieKey {
+                mac: HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any size"),
+            }
+        }
+
+        fn mac_for(&self, name: &str, value: &str) -> HmacSha256 {
+            let mut mac = self.mac.clone();
+            mac.update(name.as_bytes());
+            mac.update(b"=");
+            mac.update(value.as_bytes());
+            mac
+        }
+
+        pub fn sign_value(&self, name: &str, value: &str) -> String {
+            let signature = self.mac_for(name, value).finalize().into_bytes();
+            let mut signed = String::with_capacity(value.len() + 1 + signature.len() * 2);
+            signed.push_str(value);
+            signed.push('.');
+            for byte in signature {
+                signed.push_str(&format!("{byte:02x}"));
+            }
+            signed
+        }
+
+        /// Returns the original value if the signature matches.
+        pub fn verify_value<'a>(&self, name: &str, signed: &'a str) -> Option<&'a str> {
+            let (value, signature) = signed.rsplit_once('.')?;
+            let signature = decode_hex(signature)?;
+            self.mac_for(name, value)
+                .verify_slice(&signature)
+                .ok()
+                .map(|_| value)
+        }
+
+        pub fn signed_cookie(&self, name: impl Into<String>, value: &str) -> Cookie {
+            let name = name.into();
+            let signed = self.sign_value(&name, value);
+            Cookie::new(name, signed)
+        }
+    }
+
+    fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+        // from_str_radix would also take a sign
+        if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return None;
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect()
+    }
+
+    impl<'a> HttpRequest<'a> {
+        /// The value of a cookie set with `CookieKey::signed_cookie`, if its signature is valid.
+        pub fn get_signed_cookie(&self, name: &str, key: &CookieKey) -> Option<&'a str> {
+            self.get_cookies()
+                .into_iter()
+                .filter(|(cookie_name, _)| *cookie_name == name)
+                .find_map(|(_, value)| key.verify_value(name, value))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn verifies_signed_values() {
+            let key = CookieKey::new(b"0123456789abcdef0123456789abcdef");
+            let signed = key.sign_value("session", "user.42");
+            assert_eq!(key.verify_value("session", &signed), Some("user.42"));
+            assert_eq!(key.signed_cookie("session", "user.42").value(), signed);
+        }
+
+        #[test]
+        fn rejects_tampered_values() {
+            let key = CookieKey::new(b"0123456789abcdef0123456789abcdef");
+            let signed = key.sign_value("session", "user.42");
+            let (_, signature) = signed.rsplit_once('.').unwrap();
+
+            let verify = |signed: &str| key.verify_value("session", signed).is_some();
+            assert!(!verify(&format!("user.43.{signature}")));
+            assert!(!verify(&signed[..signed.len() - 2]));
+            assert!(!verify("user.42"));
+            assert_eq!(key.verify_value("other", &signed), None);
+            let other_key = CookieKey::new(b"another secret of at least 32 bytes");
+            assert_eq!(other_key.verify_value("session", &signed), None);
+        }
+
+        #[test]
+        fn decodes_only_hex_digits() {
+            assert_eq!(decode_hex("00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+            assert_eq!(decode_hex("+f"), None);
+            assert_eq!(decode_hex("0g"), None);
+            assert_eq!(decode_hex("abc"), None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cookie_headers() {
+        assert_eq!(
+            parse_cookie_header("a=1; b=\"two\";c; =x ;d = 4=4; e="),
+            vec![("a", "1"), ("b", "two"), ("d", "4=4"), ("e", "")]
+        );
+    }
+
+    #[test]
+    fn formats_set_cookie_headers() {
+        let cookie = Cookie::new("id", "abc")
+            .path("/")
+            .domain("example.com")
+            .max_age(Duration::from_secs(60))
+            .expires(SystemTime::UNIX_EPOCH + Duration::from_secs(784111777))
+            .http_only(true)
+            .same_site(SameSite::None);
+        assert_eq!(
+            cookie.to_header_value().unwrap(),
+            "id=abc; Path=/; Domain=example.com; Max-Age=60; \
+             Expires=Sun, 06 Nov 1994 08:49:37 GMT; Secure; HttpOnly; SameSite=None"
+        );
+        assert_eq!(
+            Cookie::removal("id").to_header_value().unwrap(),
+            "id=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_cookies() {
+        let error = |cookie: Cookie| cookie.to_header_value().unwrap_err();
+        assert_eq!(error(Cookie::new("", "a")), CookieError::InvalidName);
+        assert_eq!(error(Cookie::new("a b", "a")), CookieError::InvalidName);
+        assert_eq!(error(Cookie::new("a", "x;y")), CookieError::InvalidValue);
+        assert_eq!(error(Cookie::new("a", "é")), CookieError::InvalidValue);
+        let path = Cookie::new("a", "b").path("/\r\nX: y");
+        assert_eq!(error(path), CookieError::InvalidAttribute);
+    }
+}
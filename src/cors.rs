@@ -0,0 +1,282 @@
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+use crate::app::Application;
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponseStruct;
+use crate::status_code::StatusCode;
+
+type OriginPredicate = Box<dyn Fn(&str) -> bool>;
+
+/// Cross-Origin Resource Sharing policy, installed with `Application::cors`.
+///
+/// Nothing is allowed by default. Requests from other origins are still handled, they only
+/// lack the `Access-Control-*` headers so the browser hides the response from the page.
+pub struct Cors {
+    any_origin: bool,
+    origins: Vec<String>,
+    origin_predicates: Vec<OriginPredicate>,
+    methods: Vec<String>,
+    // None mirrors the headers the preflight asks for
+    allow_headers: Option<Vec<String>>,
+    expose_headers: Vec<String>,
+    allow_credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors {
+            any_origin: false,
+            origins: Vec::new(),
+            origin_predicates: Vec::new(),
+            methods: ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"]
+                .map(str::to_string)
+                .to_vec(),
+            allow_headers: None,
+            expose_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl Cors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers with `*`. Panics together with `allow_credentials`, that would let every site
+    /// read responses with the cookies of the user.
+    pub fn allow_any_origin(mut self) -> Self {
+        self.any_origin = true;
+        self.assert_credentials_need_origins();
+        self
+    }
+
+    /// An origin like `https://example.com`, compared case-insensitively.
+    pub fn allow_origin(mut self, origin: impl Into<String>) -> Self {
+        self.origins.push(origin.into());
+        self
+    }
+
+    pub fn allow_origins<I, S>(mut self, origins: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.origins.extend(origins.into_iter().map(Into::into));
+        self
+    }
+
+    /// Allows every origin the predicate accepts, e.g. all subdomains.
+    pub fn allow_origin_fn(mut self, predicate: impl Fn(&str) -> bool + 'static) -> Self {
+        self.origin_predicates.push(Box::new(predicate));
+        self
+    }
+
+    /// Replaces the default of `GET, HEAD, POST, PUT, PATCH, DELETE`.
+    pub fn allow_methods<I, S>(mut self, methods: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.methods = methods.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Without this, preflights are allowed every header they ask for.
+    pub fn allow_headers<I, S>(mut self, headers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allow_headers = Some(headers.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn expose_headers<I, S>(mut self, headers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.expose_headers = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Allows cookies and authorization headers. Only for explicitly allowed origins, panics
+    /// together with `allow_any_origin`.
+    pub fn allow_credentials(mut self, allow_credentials: bool) -> Self {
+        self.allow_credentials = allow_credentials;
+        self.assert_credentials_need_origins();
+        self
+    }
+
+    fn assert_credentials_need_origins(&self) {
+        assert!(
+            !(self.any_origin && self.allow_credentials),
+            "Cors can't allow credentials for any origin, list the allowed origins instead"
+        );
+    }
+
+    /// How long browsers may cache a preflight answer.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn is_origin_allowed(&self, origin: &str) -> bool {
+        self.any_origin
+            || self
+                .origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+            || self
+                .origin_predicates
+                .iter()
+                .any(|predicate| predicate(origin))
+    }
+
+    pub fn is_preflight(req: &HttpRequest) -> bool {
+        req.get_method().eq_ignore_ascii_case("options")
+            && req.get_header("origin").is_some()
+            && req.get_header("access-control-request-method").is_some()
+    }
+
+    /// Answers a preflight with 204, or with 403 if the origin or method is not allowed.
+    pub fn answer_preflight<const SSL: bool>(
+        &self,
+        res: &HttpResponseStruct<SSL>,
+        req: &HttpRequest,
+    ) {
+        let origin = req.get_header("origin").unwrap_or("");
+        let method = req
+            .get_header("access-control-request-method")
+            .unwrap_or("");
+        let method_allowed = self
+            .methods
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method));
+
+        if !self.is_origin_allowed(origin) || !method_allowed {
+            res.write_status_code(StatusCode::FORBIDDEN);
+            res.end(None, false);
+            return;
+        }
+
+        res.write_status_code(StatusCode::NO_CONTENT);
+        self.write_origin_headers(origin, |key, value| res.write_header(key, value));
+        res.write_header("Access-Control-Allow-Methods", &self.methods.join(", "));
+        match &self.allow_headers {
+            Some(headers) => {
+                if !headers.is_empty() {
+                    res.write_header("Access-Control-Allow-Headers", &headers.join(", "));
+                }
+            }
+            None => {
+                if let Some(requested) = req.get_header("access-control-request-headers") {
+                    res.write_header("Access-Control-Allow-Headers", requested);
+                }
+                res.write_header("Vary", "Access-Control-Request-Headers");
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            res.write_header("Access-Control-Max-Age", &max_age.as_secs().to_string());
+        }
+        res.end(None, false);
+    }
+
+    /// Adds the CORS headers for an allowed origin to whatever response the handler sends,
+    /// WebSocket upgrades included.
+    pub fn decorate<const SSL: bool>(&self, res: &HttpResponseStruct<SSL>, req: &HttpRequest) {
+        let Some(origin) = req.get_header("origin") else {
+            return;
+        };
+        if !self.is_origin_allowed(origin) {
+            return;
+        }
+
+        self.write_origin_headers(origin, |key, value| {
+            let _ = res.defer_header(key, value);
+        });
+        if !self.expose_headers.is_empty() {
+            let _ = res.defer_header(
+                "Access-Control-Expose-Headers",
+                &self.expose_headers.join(", "),
+            );
+        }
+    }
+
+    fn write_origin_headers(&self, origin: &str, mut write_header: impl FnMut(&str, &str)) {
+        if self.any_origin {
+            write_header("Access-Control-Allow-Origin", "*");
+            return;
+        }
+
+        write_header("Access-Control-Allow-Origin", origin);
+        // The answer depends on the origin, caches must not hand it to other origins
+        write_header("Vary", "Origin");
+        if self.allow_credentials {
+            write_header("Access-Control-Allow-Credentials", "true");
+        }
+    }
+}
+
+impl<const SSL: bool> Application<SSL> {
+    /// Applies the CORS policy to every route and WebSocket upgrade.
+    ///
+    /// Preflights are answered before any handler runs, also on paths that have no `options`
+    /// route. Other `OPTIONS` requests on those paths are left to the remaining routes.
+    pub fn cors(&mut self, cors: Cors) -> &mut Self {
+        self.before_request(move |res, req| {
+            if Cors::is_preflight(req) {
+                cors.answer_preflight(res, req);
+                return ControlFlow::Break(());
+            }
+            cors.decorate(res, req);
+            ControlFlow::Continue(())
+        });
+
+        // Gives preflights a route to reach the hook, the hook answers them
+        self.options("/*", |_, req| req.set_yield(true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "can't allow credentials for any origin")]
+    fn rejects_credentials_for_any_origin() {
+        let _ = Cors::new().allow_credentials(true).allow_any_origin();
+    }
+
+    #[test]
+    #[should_panic(expected = "can't allow credentials for any origin")]
+    fn rejects_any_origin_with_credentials() {
+        let _ = Cors::new().allow_any_origin().allow_credentials(true);
+    }
+
+    #[test]
+    fn echoes_allowed_origins_with_credentials() {
+        let cors = Cors::new()
+            .allow_origin("https://example.com")
+            .allow_credentials(true);
+        assert!(cors.is_origin_allowed("https://EXAMPLE.com"));
+        assert!(!cors.is_origin_allowed("https://evil.example"));
+
+        let mut headers = Vec::new();
+        cors.write_origin_headers("https://example.com", |key, value| {
+            headers.push(format!("{key}: {value}"))
+        });
+        assert_eq!(
+            headers,
+            [
+                "Access-Control-Allow-Origin: https://example.com",
+                "Vary: Origin",
+                "Access-Control-Allow-Credentials: true",
+            ]
+        );
+    }
+}
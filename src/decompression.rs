@@ -0,0 +1,216 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+
+use flate2::write::{DeflateDecoder, GzDecoder, ZlibDecoder};
+
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponseStruct;
+use crate::status_code::StatusCode;
+
+#[derive(Debug)]
+pub enum BodyDecodeError {
+    UnsupportedEncoding(String),
+    /// The decompressed body grew past the configured limit.
+    TooLarge,
+    Corrupt(io::Error),
+}
+
+impl BodyDecodeError {
+    /// The status to answer the request with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            BodyDecodeError::UnsupportedEncoding(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            BodyDecodeError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            BodyDecodeError::Corrupt(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for BodyDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyDecodeError::UnsupportedEncoding(encoding) => {
+                write!(f, "Unsupported content encoding: {encoding}")
+            }
+            BodyDecodeError::TooLarge => write!(f, "Decompressed body is too large"),
+            BodyDecodeError::Corrupt(e) => write!(f, "Corrupt compressed body: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for BodyDecodeError {}
+
+// Collects decoder output and refuses to grow past the limit
+struct LimitedSink {
+    buf: Vec<u8>,
+    total: usize,
+    max_size: usize,
+}
+
+impl Write for LimitedSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.total += buf.len();
+        if self.total > self.max_size {
+            return Err(io::Error::other(BodyDecodeError::TooLarge));
+        }
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Decoder {
+    Identity(LimitedSink),
+    Gzip(GzDecoder<LimitedSink>),
+    // "deflate" is meant to be zlib wrapped, but raw deflate is common enough to accept too
+    Deflate(Option<Vec<u8>>, usize),
+    Zlib(ZlibDecoder<LimitedSink>),
+    RawDeflate(DeflateDecoder<LimitedSink>),
+}
+
+struct BodyDecoder {
+    decoder: Decoder,
+    failed: bool,
+}
+
+impl BodyDecoder {
+    fn new(content_encoding: Option<&str>, max_size: usize) -> Result<Self, BodyDecodeError> {
+        let sink = LimitedSink {
+            buf: Vec::new(),
+            total: 0,
+            max_size,
+        };
+        let encoding = content_encoding.unwrap_or("").trim().to_ascii_lowercase();
+        let decoder = match encoding.as_str() {
+            "" | "identity" => Decoder::Identity(sink),
+            "gzip" | "x-gzip" => Decoder::Gzip(GzDecoder::new(sink)),
+            "deflate" => Decoder::Deflate(Some(Vec::new()), max_size),
+            _ => return Err(BodyDecodeError::UnsupportedEncoding(encoding)),
+        };
+        Ok(BodyDecoder {
+            decoder,
+            failed: false,
+        })
+    }
+
+    fn decode(&mut self, chunk: &[u8], is_end: bool) -> Result<Vec<u8>, BodyDecodeError> {
+        if let Decoder::Deflate(pending, max_size) = &mut self.decoder {
+            let mut pending = pending.take().unwrap_or_default();
+            pending.extend_from_slice(chunk);
+            if pending.len() < 2 && !is_end {
+                self.decoder = Decoder::Deflate(Some(pending), *max_size);
+                return Ok(Vec::new());
+            }
+
+            let sink = LimitedSink {
+                buf: Vec::new(),
+                total: 0,
+                max_size: *max_size,
+            };
+            self.decoder = if is_zlib_header(&pending) {
+                Decoder::Zlib(ZlibDecoder::new(sink))
+            } else {
+                Decoder::RawDeflate(DeflateDecoder::new(sink))
+            };
+            return self.decode(&pending, is_end);
+        }
+
+        let result = match &mut self.decoder {
+            Decoder::Identity(sink) => sink.write_all(chunk).map(|_| sink),
+            Decoder::Gzip(decoder) => decoder.decode_chunk(chunk, is_end),
+            Decoder::Zlib(decoder) => decoder.decode_chunk(chunk, is_end),
+            Decoder::RawDeflate(decoder) => decoder.decode_chunk(chunk, is_end),
+            Decoder::Deflate(..) => unreachable!(),
+        };
+
+        match result {
+            Ok(sink) => Ok(std::mem::take(&mut sink.buf)),
+            Err(e) => Err(match e.get_ref().and_then(|e| e.downcast_ref()) {
+                Some(BodyDecodeError::TooLarge) => BodyDecodeError::TooLarge,
+                _ => BodyDecodeError::Corrupt(e),
+            }),
+        }
+    }
+}
+
+trait StreamDecoder: Write {
+    fn sink(&mut self) -> &mut LimitedSink;
+    fn finish_stream(&mut self) -> io::Result<()>;
+
+    fn decode_chunk(&mut self, chunk: &[u8], is_end: bool) -> io::Result<&mut LimitedSink> {
+        self.write_all(chunk)?;
+        if is_end {
+            self.finish_stream()?;
+        }
+        Ok(self.sink())
+    }
+}
+
+macro_rules! impl_stream_decoder {
+    ($($decoder:ident),*) => {$(
+        impl StreamDecoder for $decoder<LimitedSink> {
+            fn sink(&mut self) -> &mut LimitedSink {
+                self.get_mut()
+            }
+
+            fn finish_stream(&mut self) -> io::Result<()> {
+                self.try_finish()
+            }
+        }
+    )*};
+}
+
+impl_stream_decoder!(GzDecoder, ZlibDecoder, DeflateDecoder);
+
+fn is_zlib_header(data: &[u8]) -> bool {
+    data.len() >= 2
+        && data[0] & 0x0f == 8
+        && (u16::from(data[0]) << 8 | u16::from(data[1])) % 31 == 0
+}
+
+impl<const SSL: bool> HttpResponseStruct<SSL> {
+    /// Like `on_data`, but decompresses `gzip` and `deflate` request bodies chunk by chunk,
+    /// according to the `Content-Encoding` of `req`.
+    ///
+    /// Once the decompressed body grows past `max_size` or turns out to be broken, the handler
+    /// gets the error as the last call and the rest of the body is dropped.
+    pub fn on_decompressed_data(
+        &mut self,
+        req: &HttpRequest,
+        max_size: usize,
+        handler: impl Fn(Result<&[u8], BodyDecodeError>, bool) + 'static,
+    ) {
+        let decoder = match BodyDecoder::new(req.get_header("content-encoding"), max_size) {
+            Ok(decoder) => RefCell::new(decoder),
+            Err(e) => {
+                // The body still has to be consumed, it is ignored like after any other error
+                self.on_data(|_, _| {});
+                handler(Err(e), true);
+                return;
+            }
+        };
+
+        self.on_data(move |chunk, is_end| {
+            let mut decoder = decoder.borrow_mut();
+            if decoder.failed {
+                return;
+            }
+            match decoder.decode(chunk, is_end) {
+                Ok(decoded) => {
+                    // Don't bother the handler with empty chunks, but it always learns about the end
+                    if !decoded.is_empty() || is_end {
+                        handler(Ok(&decoded), is_end);
+                    }
+                }
+                Err(e) => {
+                    decoder.failed = true;
+                    handler(Err(e), true);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,530 @@
+use std::cell::RefCell;
+use std::fmt;
+
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponseStruct;
+use crate::status_code::StatusCode;
+use crate::utils::parse_urlencoded_pairs;
+
+/// Parses an `application/x-www-form-urlencoded` body, repeated keys are kept in order.
+pub fn parse_urlencoded(body: &[u8]) -> Vec<(String, String)> {
+    parse_urlencoded_pairs(&String::from_utf8_lossy(body))
+}
+
+pub struct MultipartLimits {
+    pub max_parts: usize,
+    pub max_part_size: u64,
+    pub max_header_size: usize,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        MultipartLimits {
+            max_parts: 128,
+            max_part_size: 16 * 1024 * 1024,
+            max_header_size: 8 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MultipartError {
+    /// The content type is not multipart or lacks a boundary.
+    InvalidBoundary,
+    TooManyParts,
+    PartTooLarge,
+    HeadersTooLarge,
+    Malformed,
+    /// The body ended before the closing boundary.
+    Incomplete,
+}
+
+impl MultipartError {
+    /// The status to answer the request with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            MultipartError::TooManyParts
+            | MultipartError::PartTooLarge
+            | MultipartError::HeadersTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            MultipartError::InvalidBoundary => "Missing or invalid multipart boundary",
+            MultipartError::TooManyParts => "Too many multipart parts",
+            MultipartError::PartTooLarge => "Multipart part is too large",
+            MultipartError::HeadersTooLarge => "Multipart part headers are too large",
+            MultipartError::Malformed => "Malformed multipart body",
+            MultipartError::Incomplete => "Multipart body ended before the closing boundary",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for MultipartError {}
+
+#[derive(Debug, Clone, Default)]
+pub struct PartHeaders {
+    /// The `name` of the form field, from `Content-Disposition`.
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    /// All headers of the part, with lowercased names.
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+pub enum MultipartEvent<'a> {
+    PartStart(&'a PartHeaders),
+    /// A piece of the current part's body, parts usually arrive in several pieces.
+    Data(&'a [u8]),
+    PartEnd,
+    /// The closing boundary was reached, nothing follows.
+    End,
+}
+
+enum State {
+    Preamble,
+    AfterDelimiter,
+    Headers,
+    Body,
+    Epilogue,
+}
+
+/// Push parser for `multipart/form-data` bodies, fed with the chunks from `on_data`.
+///
+/// Only the unparsed tail of the previous chunk is kept, part bodies are handed out as they
+/// arrive so they can be streamed to disk.
+pub struct MultipartParser {
+    // "\r\n--boundary", the body is parsed as if it started with "\r\n"
+    delimiter: Vec<u8>,
+    limits: MultipartLimits,
+    state: State,
+    buf: Vec<u8>,
+    headers: PartHeaders,
+    parts: usize,
+    part_size: u64,
+}
+
+impl MultipartParser {
+    pub fn new(content_type: &str, limits: MultipartLimits) -> Result<Self, MultipartError> {
+        let boundary = multipart_boundary(content_type).ok_or(MultipartError::InvalidBoundary)?;
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+
+        Ok(MultipartParser {
+            delimiter,
+            limits,
+            state: State::Preamble,
+            buf: b"\r\n".to_vec(),
+            headers: PartHeaders::default(),
+            parts: 0,
+            part_size: 0,
+        })
+    }
+
+    pub fn feed(
+        &mut self,
+        chunk: &[u8],
+        mut on_event: impl FnMut(MultipartEvent<'_>),
+    ) -> Result<(), MultipartError> {
+        if let State::Epilogue = self.state {
+            return Ok(());
+        }
+        self.buf.extend_from_slice(chunk);
+
+        let mut pos = 0;
+        loop {
+            let rest = &self.buf[pos..];
+            match self.state {
+                State::Preamble => match find(rest, &self.delimiter) {
+                    Some(index) => {
+                        pos += index + self.delimiter.len();
+                        self.state = State::AfterDelimiter;
+                    }
+                    None => {
+                        pos += rest.len().saturating_sub(self.delimiter.len() - 1);
+                        break;
+                    }
+                },
+                State::AfterDelimiter => {
+                    if rest.len() < 2 {
+                        break;
+                    }
+                    if rest.starts_with(b"--") {
+                        self.state = State::Epilogue;
+                        on_event(MultipartEvent::End);
+                        break;
+                    }
+                    // Transport padding may follow the boundary before its line break
+                    let line_end = match find(rest, b"\r\n") {
+                        Some(line_end) => line_end,
+                        None if rest.len() > 256 => return Err(MultipartError::Malformed),
+                        None => break,
+                    };
+                    if rest[..line_end]
+                        .iter()
+                        .any(|byte| *byte != b' ' && *byte != b'\t')
+                    {
+                        return Err(MultipartError::Malformed);
+                    }
+                    pos += line_end + 2;
+                    self.state = State::Headers;
+                }
+                State::Headers => {
+                    let headers_end = if rest.starts_with(b"\r\n") {
+                        Some((0, 2))
+                    } else {
+                        find(rest, b"\r\n\r\n").map(|index| (index, index + 4))
+                    };
+                    let (headers_end, skip) = match headers_end {
+                        Some(headers_end) => headers_end,
+                        None if rest.len() > self.limits.max_header_size => {
+                            return Err(MultipartError::HeadersTooLarge)
+                        }
+                        None => break,
+                    };
+                    if headers_end > self.limits.max_header_size {
+                        return Err(MultipartError::HeadersTooLarge);
+                    }
+
+                    self.parts += 1;
+                    if self.parts > self.limits.max_parts {
+                        return Err(MultipartError::TooManyParts);
+                    }
+                    self.headers = parse_part_headers(&rest[..headers_end])?;
+                    self.part_size = 0;
+                    pos += skip;
+                    self.state = State::Body;
+                    on_event(MultipartEvent::PartStart(&self.headers));
+                }
+                State::Body => {
+                    let (data_len, found) = match find(rest, &self.delimiter) {
+                        Some(index) => (index, true),
+                        // The end of the buffer could be the start of a delimiter
+                        None => (rest.len().saturating_sub(self.delimiter.len() - 1), false),
+                    };
+
+                    self.part_size += data_len as u64;
+                    if self.part_size > self.limits.max_part_size {
+                        return Err(MultipartError::PartTooLarge);
+                    }
+                    if data_len > 0 {
+                        on_event(MultipartEvent::Data(&rest[..data_len]));
+                    }
+                    pos += data_len;
+
+                    if !found {
+                        break;
+                    }
+                    pos += self.delimiter.len();
+                    self.state = State::AfterDelimiter;
+                    on_event(MultipartEvent::PartEnd);
+                }
+                State::Epilogue => break,
+            }
+        }
+
+        self.buf.drain(..pos);
+        if let State::Epilogue = self.state {
+            self.buf = Vec::new();
+        }
+        Ok(())
+    }
+
+    /// Checks that the body was complete, to be called after the last chunk.
+    pub fn finish(&self) -> Result<(), MultipartError> {
+        match self.state {
+            State::Epilogue => Ok(()),
+            _ => Err(MultipartError::Incomplete),
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() || haystack.len() < needle.len() {
+        return None;
+    }
+    let last = haystack.len() - needle.len();
+    let mut start = 0;
+    while start <= last {
+        let index = start
+            + haystack[start..=last]
+                .iter()
+                .position(|byte| *byte == needle[0])?;
+        if haystack[index..].starts_with(needle) {
+            return Some(index);
+        }
+        start = index + 1;
+    }
+    None
+}
+
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let mime = params.next()?.trim();
+    if !mime.to_ascii_lowercase().starts_with("multipart/") {
+        return None;
+    }
+
+    let boundary = params.find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| unquote(value.trim()))
+    })?;
+    // RFC 2046 limits boundaries to 70 characters
+    if boundary.is_empty() || boundary.len() > 70 {
+        return None;
+    }
+    Some(boundary)
+}
+
+fn parse_part_headers(raw: &[u8]) -> Result<PartHeaders, MultipartError> {
+    let raw = String::from_utf8_lossy(raw);
+    let mut headers = PartHeaders::default();
+
+    for line in raw.split("\r\n").filter(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').ok_or(MultipartError::Malformed)?;
+        let name = name.trim().to_ascii_lowercase();
+        let value = value.trim().to_string();
+
+        match name.as_str() {
+            "content-disposition" => {
+                for param in split_params(&value).into_iter().skip(1) {
+                    let Some((key, param_value)) = param.split_once('=') else {
+                        continue;
+                    };
+                    match key.trim().to_ascii_lowercase().as_str() {
+                        "name" => headers.name = Some(unquote(param_value.trim())),
+                        "filename" => headers.filename = Some(unquote(param_value.trim())),
+                        _ => {}
+                    }
+                }
+            }
+            "content-type" => headers.content_type = Some(value.clone()),
+            _ => {}
+        }
+        headers.headers.push((name, value));
+    }
+    Ok(headers)
+}
+
+// Splits on ';' outside of quoted strings
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (index, char) in value.char_indices() {
+        match char {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                params.push(&value[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    params.push(&value[start..]);
+    params
+}
+
+fn unquote(value: &str) -> String {
+    match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        Some(quoted) => {
+            let mut unquoted = String::with_capacity(quoted.len());
+            let mut chars = quoted.chars();
+            while let Some(char) = chars.next() {
+                match char {
+                    '\\' => unquoted.extend(chars.next()),
+                    char => unquoted.push(char),
+                }
+            }
+            unquoted
+        }
+        None => value.to_string(),
+    }
+}
+
+impl<const SSL: bool> HttpResponseStruct<SSL> {
+    /// Parses the request body as `multipart/form-data` while it arrives, see `MultipartParser`.
+    ///
+    /// An error is always the last call of the handler, the rest of the body is dropped then.
+    pub fn on_multipart_data(
+        &mut self,
+        req: &HttpRequest,
+        limits: MultipartLimits,
+        handler: impl Fn(Result<MultipartEvent<'_>, MultipartError>) + 'static,
+    ) {
+        let content_type = req.get_header("content-type").unwrap_or("");
+        let parser = match MultipartParser::new(content_type, limits) {
+            Ok(parser) => RefCell::new(Some(parser)),
+            Err(e) => {
+                self.on_data(|_, _| {});
+                handler(Err(e));
+                return;
+            }
+        };
+
+        self.on_data(move |chunk, is_end| {
+            let mut parser_slot = parser.borrow_mut();
+            let Some(parser) = parser_slot.as_mut() else {
+                return;
+            };
+
+            let result = parser
+                .feed(chunk, |event| handler(Ok(event)))
+                .and_then(|_| if is_end { parser.finish() } else { Ok(()) });
+            if let Err(e) = result {
+                *parser_slot = None;
+                handler(Err(e));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT_TYPE: &str = "multipart/form-data; boundary=\"xyz\"";
+    const BODY: &[u8] = b"preamble\r\n--xyz\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+        hello\r\n--xyz  \r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"a;b.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        line one\r\n--xy line two\r\n--xyz--\r\nepilogue";
+
+    type Part = (Option<String>, Vec<u8>);
+
+    // The name and body of every part, fed in chunks of `chunk_size`
+    fn parse(
+        body: &[u8],
+        chunk_size: usize,
+        limits: MultipartLimits,
+    ) -> Result<Vec<Part>, MultipartError> {
+        let mut parser = MultipartParser::new(CONTENT_TYPE, limits)?;
+        let mut parts = Vec::new();
+        for chunk in body.chunks(chunk_size) {
+            parser.feed(chunk, |event| match event {
+                MultipartEvent::PartStart(headers) => parts.push((headers.name.clone(), vec![])),
+                MultipartEvent::Data(data) => parts.last_mut().unwrap().1.extend_from_slice(data),
+                MultipartEvent::PartEnd | MultipartEvent::End => {}
+            })?;
+        }
+        parser.finish()?;
+        Ok(parts)
+    }
+
+    fn limits(max_parts: usize, max_part_size: u64, max_header_size: usize) -> MultipartLimits {
+        MultipartLimits {
+            max_parts,
+            max_part_size,
+            max_header_size,
+        }
+    }
+
+    #[test]
+    fn parses_parts_split_across_chunks() {
+        let expected = vec![
+            (Some("title".to_string()), b"hello".to_vec()),
+            (
+                Some("file".to_string()),
+                b"line one\r\n--xy line two".to_vec(),
+            ),
+        ];
+        for chunk_size in 1..=BODY.len() {
+            let parts = parse(BODY, chunk_size, MultipartLimits::default()).unwrap();
+            assert_eq!(parts, expected, "chunk size {chunk_size}");
+        }
+    }
+
+    #[test]
+    fn reads_part_headers() {
+        let mut parser = MultipartParser::new(CONTENT_TYPE, MultipartLimits::default()).unwrap();
+        let mut files = Vec::new();
+        parser
+            .feed(BODY, |event| {
+                if let MultipartEvent::PartStart(headers) = event {
+                    files.push((headers.filename.clone(), headers.content_type.clone()));
+                }
+            })
+            .unwrap();
+        assert_eq!(
+            files,
+            [
+                (None, None),
+                (Some("a;b.txt".to_string()), Some("text/plain".to_string()))
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_the_epilogue() {
+        let mut parser = MultipartParser::new(CONTENT_TYPE, MultipartLimits::default()).unwrap();
+        parser.feed(BODY, |_| {}).unwrap();
+        parser.feed(b"\r\n--xyz\r\n\r\nmore", |_| panic!()).unwrap();
+        assert!(parser.finish().is_ok());
+    }
+
+    #[test]
+    fn rejects_missing_closing_boundary() {
+        let body = b"--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue";
+        assert!(matches!(
+            parse(body, 4, MultipartLimits::default()),
+            Err(MultipartError::Incomplete)
+        ));
+        assert!(matches!(
+            parse(b"", 1, MultipartLimits::default()),
+            Err(MultipartError::Incomplete)
+        ));
+    }
+
+    #[test]
+    fn enforces_limits() {
+        assert!(parse(BODY, 7, limits(2, 23, 96)).is_ok());
+        assert!(matches!(
+            parse(BODY, 7, limits(1, 23, 96)),
+            Err(MultipartError::TooManyParts)
+        ));
+        assert!(matches!(
+            parse(BODY, 7, limits(2, 22, 96)),
+            Err(MultipartError::PartTooLarge)
+        ));
+        assert!(matches!(
+            parse(BODY, 7, limits(2, 23, 88)),
+            Err(MultipartError::HeadersTooLarge)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_boundaries() {
+        let new = |content_type| MultipartParser::new(content_type, MultipartLimits::default());
+        assert!(new("multipart/form-data; boundary=abc").is_ok());
+        assert!(new("text/plain; boundary=abc").is_err());
+        assert!(new("multipart/form-data").is_err());
+        assert!(new("multipart/form-data; boundary=\"\"").is_err());
+    }
+
+    #[test]
+    fn parses_urlencoded_forms() {
+        assert_eq!(
+            parse_urlencoded(b"name=J%C3%BCrgen+K&tag=a&tag=b"),
+            vec![
+                ("name".to_string(), "Jürgen K".to_string()),
+                ("tag".to_string(), "a".to_string()),
+                ("tag".to_string(), "b".to_string()),
+            ]
+        );
+    }
+}
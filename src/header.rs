@@ -0,0 +1,117 @@
+use std::fmt;
+
+pub const ACCEPT: &str = "Accept";
+pub const ACCEPT_ENCODING: &str = "Accept-Encoding";
+pub const ACCEPT_LANGUAGE: &str = "Accept-Language";
+pub const ACCEPT_RANGES: &str = "Accept-Ranges";
+pub const ACCESS_CONTROL_ALLOW_CREDENTIALS: &str = "Access-Control-Allow-Credentials";
+pub const ACCESS_CONTROL_ALLOW_HEADERS: &str = "Access-Control-Allow-Headers";
+pub const ACCESS_CONTROL_ALLOW_METHODS: &str = "Access-Control-Allow-Methods";
+pub const ACCESS_CONTROL_ALLOW_ORIGIN: &str = "Access-Control-Allow-Origin";
+pub const ACCESS_CONTROL_EXPOSE_HEADERS: &str = "Access-Control-Expose-Headers";
+pub const ACCESS_CONTROL_MAX_AGE: &str = "Access-Control-Max-Age";
+pub const ACCESS_CONTROL_REQUEST_HEADERS: &str = "Access-Control-Request-Headers";
+pub const ACCESS_CONTROL_REQUEST_METHOD: &str = "Access-Control-Request-Method";
+pub const AGE: &str = "Age";
+pub const ALLOW: &str = "Allow";
+pub const AUTHORIZATION: &str = "Authorization";
+pub const CACHE_CONTROL: &str = "Cache-Control";
+pub const CONNECTION: &str = "Connection";
+pub const CONTENT_DISPOSITION: &str = "Content-Disposition";
+pub const CONTENT_ENCODING: &str = "Content-Encoding";
+pub const CONTENT_LANGUAGE: &str = "Content-Language";
+pub const CONTENT_LENGTH: &str = "Content-Length";
+pub const CONTENT_LOCATION: &str = "Content-Location";
+pub const CONTENT_RANGE: &str = "Content-Range";
+pub const CONTENT_SECURITY_POLICY: &str = "Content-Security-Policy";
+pub const CONTENT_TYPE: &str = "Content-Type";
+pub const COOKIE: &str = "Cookie";
+pub const DATE: &str = "Date";
+pub const ETAG: &str = "ETag";
+pub const EXPECT: &str = "Expect";
+pub const EXPIRES: &str = "Expires";
+pub const FORWARDED: &str = "Forwarded";
+pub const HOST: &str = "Host";
+pub const IF_MATCH: &str = "If-Match";
+pub const IF_MODIFIED_SINCE: &str = "If-Modified-Since";
+pub const IF_NONE_MATCH: &str = "If-None-Match";
+pub const IF_RANGE: &str = "If-Range";
+pub const IF_UNMODIFIED_SINCE: &str = "If-Unmodified-Since";
+pub const LAST_EVENT_ID: &str = "Last-Event-ID";
+pub const LAST_MODIFIED: &str = "Last-Modified";
+pub const LINK: &str = "Link";
+pub const LOCATION: &str = "Location";
+pub const ORIGIN: &str = "Origin";
+pub const PRAGMA: &str = "Pragma";
+pub const RANGE: &str = "Range";
+pub const REFERER: &str = "Referer";
+pub const REFERRER_POLICY: &str = "Referrer-Policy";
+pub const RETRY_AFTER: &str = "Retry-After";
+pub const SEC_WEBSOCKET_ACCEPT: &str = "Sec-WebSocket-Accept";
+pub const SEC_WEBSOCKET_EXTENSIONS: &str = "Sec-WebSocket-Extensions";
+pub const SEC_WEBSOCKET_KEY: &str = "Sec-WebSocket-Key";
+pub const SEC_WEBSOCKET_PROTOCOL: &str = "Sec-WebSocket-Protocol";
+pub const SEC_WEBSOCKET_VERSION: &str = "Sec-WebSocket-Version";
+pub const SERVER: &str = "Server";
+pub const SET_COOKIE: &str = "Set-Cookie";
+pub const STRICT_TRANSPORT_SECURITY: &str = "Strict-Transport-Security";
+pub const TE: &str = "TE";
+pub const TRAILER: &str = "Trailer";
+pub const TRANSFER_ENCODING: &str = "Transfer-Encoding";
+pub const UPGRADE: &str = "Upgrade";
+pub const USER_AGENT: &str = "User-Agent";
+pub const VARY: &str = "Vary";
+pub const VIA: &str = "Via";
+pub const WWW_AUTHENTICATE: &str = "WWW-Authenticate";
+pub const X_CONTENT_TYPE_OPTIONS: &str = "X-Content-Type-Options";
+pub const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+pub const X_FORWARDED_HOST: &str = "X-Forwarded-Host";
+pub const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+pub const X_FRAME_OPTIONS: &str = "X-Frame-Options";
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum InvalidHeader {
+    Name,
+    Value,
+}
+
+impl fmt::Display for InvalidHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidHeader::Name => f.write_str("Header name is not a valid token"),
+            InvalidHeader::Value => f.write_str("Header value contains CR, LF or NUL"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidHeader {}
+
+/// Header names must be RFC 9110 tokens.
+pub fn is_valid_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Rejects CR, LF and NUL, which would let a value end the header or the whole head.
+pub fn is_valid_header_value(value: &str) -> bool {
+    is_valid_header_value_bytes(value.as_bytes())
+}
+
+// Values may carry obs-text, bytes that aren't UTF-8
+pub(crate) fn is_valid_header_value_bytes(value: &[u8]) -> bool {
+    !value
+        .iter()
+        .any(|byte| matches!(byte, b'\r' | b'\n' | b'\0'))
+}
+
+pub(crate) fn validate_header(name: &str, value: &str) -> Result<(), InvalidHeader> {
+    if !is_valid_header_name(name) {
+        return Err(InvalidHeader::Name);
+    }
+    if !is_valid_header_value(value) {
+        return Err(InvalidHeader::Value);
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use http::header::{HeaderName, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING};
+use http::{Method, Request, Response};
+
+use crate::header::{is_valid_header_name, is_valid_header_value_bytes, InvalidHeader};
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponseStruct;
+
+impl HttpRequest<'_> {
+    /// Copies method, URI and headers into an `http::Request`, the body has to be read
+    /// separately with `on_data`.
+    pub fn to_http_request(&self) -> Result<Request<()>, http::Error> {
+        let method = Method::from_bytes(self.get_case_sensitive_method().as_bytes())?;
+        let mut builder = Request::builder().method(method).uri(self.get_full_url());
+
+        if let Some(headers) = builder.headers_mut() {
+            let source = match &self.headers {
+                Some(headers) => headers.clone(),
+                None => self.read_headers(),
+            };
+            for (name, value) in source {
+                let name = HeaderName::from_bytes(name.as_bytes())?;
+                let value = HeaderValue::from_str(value)?;
+                headers.append(name, value);
+            }
+        }
+
+        builder.body(())
+    }
+}
+
+impl TryFrom<&HttpRequest<'_>> for Request<()> {
+    type Error = http::Error;
+
+    fn try_from(req: &HttpRequest<'_>) -> Result<Self, Self::Error> {
+        req.to_http_request()
+    }
+}
+
+impl<const SSL: bool> HttpResponseStruct<SSL> {
+    /// Sends status, headers and body of an `http::Response` and ends the response.
+    ///
+    /// `Content-Length` and `Transfer-Encoding` are skipped, uWS frames the body itself.
+    /// Nothing is written if a header is invalid, the request can still be answered otherwise.
+    pub fn send_http_response<B: AsRef<[u8]>>(
+        &self,
+        response: Response<B>,
+        close_connection: bool,
+    ) -> Result<(), InvalidHeader> {
+        let (parts, body) = response.into_parts();
+        for (name, value) in parts.headers.iter() {
+            if !is_valid_header_name(name.as_str()) {
+                return Err(InvalidHeader::Name);
+            }
+            if !is_valid_header_value_bytes(value.as_bytes()) {
+                return Err(InvalidHeader::Value);
+            }
+        }
+
+        let status = parts.status;
+        let status = match status.canonical_reason() {
+            Some(reason) => format!("{} {reason}", status.as_str()),
+            None => status.as_str().to_string(),
+        };
+        // `http` only holds three digit codes with their canonical reasons
+        self.write_status_unchecked(&status);
+
+        for (name, value) in parts.headers.iter() {
+            if name == CONTENT_LENGTH || name == TRANSFER_ENCODING {
+                continue;
+            }
+            // Written as they are, values may hold bytes that aren't UTF-8
+            self.write_header_bytes_unchecked(name.as_str(), value.as_bytes());
+        }
+
+        self.end(Some(body.as_ref()), close_connection);
+        Ok(())
+    }
+}
@@ -1,54 +1,68 @@
-use crate::utils::{read_str_from, read_str_from_ptr};
+use crate::utils::{parse_urlencoded_pairs, read_str_from, read_str_from_ptr};
 use libuwebsockets_sys::{
     uws_req_for_each_header, uws_req_get_case_sensitive_method, uws_req_get_full_url,
     uws_req_get_header, uws_req_get_method, uws_req_get_parameter, uws_req_get_query,
     uws_req_get_url, uws_req_get_yield, uws_req_is_ancient, uws_req_set_yield, uws_req_t,
 };
+use std::cell::Cell;
 use std::ffi::{c_char, c_void};
+use std::marker::PhantomData;
+use std::net::IpAddr;
 use std::ptr::null_mut;
 
-pub struct HttpRequest {
+/// The request is only valid during the synchronous handler call, uWS reuses its memory
+/// right after. The lifetime keeps it and everything borrowed from it inside the handler,
+/// use `to_owned` to keep the data around for longer. Being tied to the thread of the
+/// handler it is neither `Send` nor `Sync`.
+pub struct HttpRequest<'a> {
     pub(crate) native: *mut uws_req_t,
-    pub(crate) headers: Option<Vec<(&'static str, &'static str)>>,
+    pub(crate) headers: Option<Vec<(&'a str, &'a str)>>,
+    pub(crate) client_ip: Cell<Option<IpAddr>>,
+    _request: PhantomData<&'a uws_req_t>,
 }
 
-unsafe impl Sync for HttpRequest {}
-unsafe impl Send for HttpRequest {}
-
-impl HttpRequest {
-    pub fn new(native: *mut uws_req_t) -> Self {
+impl HttpRequest<'_> {
+    pub(crate) fn new(native: *mut uws_req_t) -> Self {
         HttpRequest {
             native,
             headers: None,
+            client_ip: Cell::new(None),
+            _request: PhantomData,
         }
     }
 }
 
 #[cfg(feature = "native-access")]
-impl HttpRequest {
+impl HttpRequest<'_> {
     pub fn get_native(&self) -> *mut uws_req_t {
         self.native
     }
 }
 
-impl HttpRequest {
-    pub fn get_full_url(&self) -> &str {
+impl<'a> HttpRequest<'a> {
+    pub fn get_full_url(&self) -> &'a str {
         unsafe { read_str_from(self.native, uws_req_get_full_url) }
     }
 
-    pub fn get_url(&self) -> &str {
+    /// The client address found by `Application::resolve_client_ip`, None without a resolver.
+    /// `HttpResponseStruct::client_ip` falls back to the TCP peer instead.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.client_ip.get()
+    }
+
+    pub fn get_url(&self) -> &'a str {
         unsafe { read_str_from(self.native, uws_req_get_url) }
     }
 
-    pub fn get_method(&self) -> &str {
+    pub fn get_method(&self) -> &'a str {
         unsafe { read_str_from(self.native, uws_req_get_method) }
     }
 
-    pub fn get_case_sensitive_method(&self) -> &str {
+    pub fn get_case_sensitive_method(&self) -> &'a str {
         unsafe { read_str_from(self.native, uws_req_get_case_sensitive_method) }
     }
 
-    pub fn get_query(&self, key: &str) -> Option<&str> {
+    pub fn get_query(&self, key: &str) -> Option<&'a str> {
         let key_ptr = key.as_ptr() as *const c_char;
         let key_len = key.len();
         let mut buf: *const c_char = null_mut();
@@ -68,6 +82,26 @@ impl HttpRequest {
         Some(unsafe { read_str_from_ptr(buf as *const c_char, len) })
     }
 
+    /// The raw query string, without the leading `?`.
+    pub fn get_query_string(&self) -> &'a str {
+        let full_url = self.get_full_url();
+        full_url
+            .split_once('?')
+            .map(|(_, query)| query)
+            .unwrap_or("")
+    }
+
+    /// Every query parameter in order, percent-decoded. Repeated keys show up once per value.
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        parse_urlencoded_pairs(self.get_query_string())
+    }
+
+    /// Deserializes the query string with `serde_urlencoded`.
+    #[cfg(feature = "serde")]
+    pub fn query<T: serde::de::DeserializeOwned>(&self) -> Result<T, serde_urlencoded::de::Error> {
+        serde_urlencoded::from_str(self.get_query_string())
+    }
+
     pub fn is_ancient(&self) -> bool {
         unsafe { uws_req_is_ancient(self.native) }
     }
@@ -80,7 +114,7 @@ impl HttpRequest {
         unsafe { uws_req_set_yield(self.native, yield_opt) }
     }
 
-    pub fn get_header(&self, header_key: &str) -> Option<&str> {
+    pub fn get_header(&self, header_key: &str) -> Option<&'a str> {
         let header_key_ptr = header_key.as_ptr();
         let header_len = header_key.len();
         let mut buf: *const c_char = null_mut();
@@ -99,7 +133,7 @@ impl HttpRequest {
         Some(unsafe { read_str_from_ptr(buf as *const c_char, len) })
     }
 
-    pub fn get_parameter(&self, index: u16) -> Option<&str> {
+    pub fn get_parameter(&self, index: u16) -> Option<&'a str> {
         let mut buf: *const c_char = null_mut();
         let len =
             unsafe { uws_req_get_parameter(self.native, index, &mut buf as *mut *const c_char) };
@@ -110,18 +144,80 @@ impl HttpRequest {
         Some(unsafe { read_str_from_ptr(buf as *const c_char, len) })
     }
 
-    pub fn get_headers(&mut self) -> &Vec<(&str, &str)> {
+    pub fn get_headers(&mut self) -> &Vec<(&'a str, &'a str)> {
         if self.headers.is_none() {
-            let mut buf: Vec<(&str, &str)> = Vec::with_capacity(30);
-            let buf_ptr: *mut Vec<(&str, &str)> = &mut buf;
-            unsafe {
-                uws_req_for_each_header(self.native, Some(header_iterator), buf_ptr as *mut c_void)
-            }
-            self.headers = Some(buf);
+            self.headers = Some(self.read_headers());
         }
 
         self.headers.as_ref().unwrap()
     }
+
+    pub(crate) fn read_headers(&self) -> Vec<(&'a str, &'a str)> {
+        let mut buf: Vec<(&str, &str)> = Vec::with_capacity(30);
+        let buf_ptr: *mut Vec<(&str, &str)> = &mut buf;
+        unsafe {
+            uws_req_for_each_header(self.native, Some(header_iterator), buf_ptr as *mut c_void)
+        }
+        buf
+    }
+
+    /// Route parameters in order, e.g. `:id` of `/users/:id`.
+    pub fn get_parameters(&self) -> Vec<&'a str> {
+        (0..).map_while(|index| self.get_parameter(index)).collect()
+    }
+
+    /// Copies everything out of the request, so it can be used after the handler returned.
+    pub fn to_owned(&self) -> OwnedRequest {
+        let headers = match &self.headers {
+            Some(headers) => headers.clone(),
+            None => self.read_headers(),
+        };
+
+        OwnedRequest {
+            method: self.get_method().to_string(),
+            url: self.get_url().to_string(),
+            query: self.get_query_string().to_string(),
+            headers: headers
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            parameters: self
+                .get_parameters()
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+}
+
+/// A copy of an `HttpRequest` that outlives the handler call.
+#[derive(Debug, Clone)]
+pub struct OwnedRequest {
+    /// Lowercased, like `HttpRequest::get_method`.
+    pub method: String,
+    pub url: String,
+    /// The raw query string, without the leading `?`.
+    pub query: String,
+    /// Header names are lowercased by uWS.
+    pub headers: Vec<(String, String)>,
+    pub parameters: Vec<String>,
+}
+
+impl OwnedRequest {
+    pub fn get_header(&self, header_key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(header_key))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_parameter(&self, index: usize) -> Option<&str> {
+        self.parameters.get(index).map(String::as_str)
+    }
+
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        parse_urlencoded_pairs(&self.query)
+    }
 }
 
 unsafe extern "C" fn header_iterator(
@@ -1,19 +1,52 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ffi::{c_char, c_int, c_void};
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr};
 use std::ptr::{null, null_mut};
 
 use libuwebsockets_sys::{
-  uws_res_cork, uws_res_end, uws_res_end_without_body, uws_res_get_remote_address,
-  uws_res_get_remote_address_as_text, uws_res_get_write_offset, uws_res_has_responded,
-  uws_res_on_aborted, uws_res_on_data, uws_res_on_writable, uws_res_override_write_offset,
-  uws_res_pause, uws_res_resume, uws_res_t, uws_res_try_end, uws_res_upgrade, uws_res_write,
-  uws_res_write_continue, uws_res_write_header, uws_res_write_header_int, uws_res_write_status,
-  uws_try_end_result_t,
+    us_socket_close, us_socket_remote_port, us_socket_t, uws_res_cork, uws_res_end,
+    uws_res_end_without_body, uws_res_get_remote_address, uws_res_get_write_offset,
+    uws_res_has_responded, uws_res_on_writable, uws_res_override_write_offset, uws_res_pause,
+    uws_res_resume, uws_res_t, uws_res_try_end, uws_res_upgrade, uws_res_write,
+    uws_res_write_continue, uws_res_write_header, uws_res_write_header_int, uws_res_write_status,
+    uws_try_end_result_t,
 };
 
+use crate::chunked_writer::ChunkedWriter;
+use crate::connection_limits::settle_upgrade;
+use crate::header::{is_valid_header_name, is_valid_header_value, validate_header, InvalidHeader};
 use crate::http_request::HttpRequest;
-use crate::utils::{read_buf_from_ptr, read_str_from_with_ssl};
+use crate::proxy_protocol::{source_address, take_source_address};
+use crate::request_timeout::{
+    await_headers, disarm, mark_started, set_on_aborted, set_on_data, stop_header_timeout,
+};
+use crate::status_code::StatusCode;
+use crate::utils::{ip_from_bytes, read_buf_from_ptr};
+use crate::websocket::{UpgradedAddresses, UpgradingAddresses};
 use crate::websocket_behavior::UpgradeContext;
 
+// Keeps what request hooks found out about the current request of a socket
+#[derive(Default)]
+struct ResponseState {
+    // See `HttpResponseStruct::defer_header`
+    deferred_headers: Vec<(String, String)>,
+    client_ip: Option<IpAddr>,
+}
+
+impl ResponseState {
+    fn is_empty(&self) -> bool {
+        self.deferred_headers.is_empty() && self.client_ip.is_none()
+    }
+}
+
+thread_local! {
+    // Keyed by the native response. Entries are replaced by the next request on the same
+    // address, so this never holds more than one entry per socket.
+    static RESPONSE_STATE: RefCell<HashMap<usize, ResponseState>> = RefCell::new(HashMap::new());
+}
+
 pub(crate) type OnDataHandler = Box<dyn Fn(&[u8], bool)>;
 pub(crate) type OnWritableHandler = Box<dyn Fn(u64) -> bool>;
 
@@ -52,7 +85,7 @@ impl<const SSL: bool> HttpResponseStruct<SSL> {
 }
 
 impl<const SSL: bool> HttpResponseStruct<SSL> {
-    pub fn default_upgrade(res: HttpResponse, req: HttpRequest, context: UpgradeContext) {
+    pub fn default_upgrade(res: Self, req: HttpRequest, context: UpgradeContext) {
         let ws_key_string = req
             .get_header("sec-websocket-key")
             .expect("There is no sec-websocket-key in req headers");
@@ -91,12 +124,7 @@ impl<const SSL: bool> HttpResponseStruct<SSL> {
 
         self.on_data_ptr = Some(user_data);
         unsafe {
-            uws_res_on_data(
-                SSL as c_int,
-                self.native,
-                Some(on_data),
-                user_data as *mut c_void,
-            );
+            set_on_data::<SSL>(self.native, on_data, user_data as *mut c_void);
         }
     }
 
@@ -123,14 +151,7 @@ impl<const SSL: bool> HttpResponseStruct<SSL> {
 
         let native_callback = if SSL { ssl_on_abort } else { on_abort };
 
-        unsafe {
-            uws_res_on_aborted(
-                SSL as i32,
-                self.native,
-                Some(native_callback),
-                http_response as *mut c_void,
-            )
-        }
+        unsafe { set_on_aborted::<SSL>(self.native, native_callback, http_response as *mut c_void) }
 
         self
     }
@@ -143,6 +164,7 @@ impl<const SSL: bool> HttpResponseStruct<SSL> {
     }
 
     pub fn end(&self, data: Option<&[u8]>, close_connection: bool) {
+        self.flush_deferred_headers();
         unsafe {
             let (data, length) = match data {
                 Some(data) => (data.as_ptr(), data.len()),
@@ -156,6 +178,7 @@ impl<const SSL: bool> HttpResponseStruct<SSL> {
                 close_connection,
             )
         }
+        self.complete_request();
         self.deinit();
         unsafe {
             let _ = self.on_abort_ptr.map(|p| Box::from_raw(p));
@@ -168,6 +191,7 @@ impl<const SSL: bool> HttpResponseStruct<SSL> {
         total_size: u64,
         close_connection: bool,
     ) -> TryEndResult<SSL> {
+        self.flush_deferred_headers();
         let res: TryEndResult<SSL> = unsafe {
             let (data, length) = match data {
                 Some(data) => (data.as_ptr(), data.len()),
@@ -185,6 +209,7 @@ impl<const SSL: bool> HttpResponseStruct<SSL> {
         .into();
 
         if res.has_responded {
+            self.complete_request();
             self.deinit();
             unsafe {
                 let _ = self.on_abort_ptr.map(|p| Box::from_raw(p));
@@ -194,6 +219,13 @@ impl<const SSL: bool> HttpResponseStruct<SSL> {
         res
     }
 
+    // The request is answered or upgraded, its timeouts and upgrade reservation are done
+    fn complete_request(&self) {
+        disarm::<SSL>(self.native);
+        await_headers::<SSL>(self.native);
+        settle_upgrade(self.native);
+    }
+
     pub fn pause(&self) {
         unsafe { uws_res_pause(SSL as c_int, self.native) }
     }
@@ -203,6 +235,7 @@ impl<const SSL: bool> HttpResponseStruct<SSL> {
     }
 
     pub fn write(&self, data: &[u8]) -> bool {
+        self.flush_deferred_headers();
         let data_len = data.len();
         let data_ptr = data.as_ptr() as *const c_char;
         unsafe { uws_res_write(SSL as c_int, self.native, data_ptr, data_len) }
@@ -212,15 +245,50 @@ impl<const SSL: bool> HttpResponseStruct<SSL> {
         unsafe { uws_res_write_continue(SSL as c_int, self.native) }
     }
 
+    /// A status containing CR, LF or NUL closes the connection instead, uWS would otherwise
+    /// answer with its default `200 OK`.
+    #[deprecated(note = "use `write_status_code`, which can't be invalid")]
     pub fn write_status(&self, status: &str) {
+        debug_assert!(is_valid_header_value(status), "Invalid status {status:?}");
+        if !is_valid_header_value(status) {
+            self.close();
+            return;
+        }
+        self.write_status_unchecked(status);
+    }
+
+    pub(crate) fn write_status_unchecked(&self, status: &str) {
         let len = status.len();
         let status_ptr = status.as_ptr() as *const c_char;
         unsafe {
             uws_res_write_status(SSL as c_int, self.native, status_ptr, len);
         }
+        self.flush_deferred_headers();
     }
 
+    pub fn write_status_code(&self, status: StatusCode) {
+        self.write_status_unchecked(&status.to_string());
+    }
+
+    /// Invalid headers are not written, as they could inject headers or even a whole response.
+    /// Debug builds panic on them, use `try_write_header` for names and values from outside.
     pub fn write_header(&self, key: &str, value: &str) {
+        let written = self.try_write_header(key, value);
+        debug_assert!(written.is_ok(), "Invalid header {key:?}: {value:?}");
+    }
+
+    pub fn try_write_header(&self, key: &str, value: &str) -> Result<(), InvalidHeader> {
+        validate_header(key, value)?;
+        self.flush_deferred_headers();
+        self.write_header_unchecked(key, value);
+        Ok(())
+    }
+
+    fn write_header_unchecked(&self, key: &str, value: &str) {
+        self.write_header_bytes_unchecked(key, value.as_bytes());
+    }
+
+    pub(crate) fn write_header_bytes_unchecked(&self, key: &str, value: &[u8]) {
         let key_len = key.len();
         let key_ptr = key.as_ptr() as *const c_char;
         let value_len = value.len();
@@ -237,8 +305,86 @@ impl<const SSL: bool> HttpResponseStruct<SSL> {
         }
     }
 
+    /// Adds a header that is written right after the status, whichever status the handler
+    /// picks later. Lets request hooks decorate responses they don't answer themselves.
+    pub fn defer_header(&self, key: &str, value: &str) -> Result<(), InvalidHeader> {
+        validate_header(key, value)?;
+        RESPONSE_STATE.with(|state| {
+            state
+                .borrow_mut()
+                .entry(self.native as usize)
+                .or_default()
+                .deferred_headers
+                .push((key.to_string(), value.to_string()))
+        });
+        Ok(())
+    }
+
+    // Responses share the socket's address, a new request must not inherit leftovers of an
+    // earlier one, e.g. headers of a response that was aborted before writing its status
+    pub(crate) fn clear_request_state(&self) {
+        RESPONSE_STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            if !state.is_empty() {
+                state.remove(&(self.native as usize));
+            }
+        });
+    }
+
+    fn take_deferred_headers(&self) -> Option<Vec<(String, String)>> {
+        RESPONSE_STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let entry = state.get_mut(&(self.native as usize))?;
+            let headers = std::mem::take(&mut entry.deferred_headers);
+            if entry.is_empty() {
+                state.remove(&(self.native as usize));
+            }
+            (!headers.is_empty()).then_some(headers)
+        })
+    }
+
+    pub(crate) fn set_client_ip(&self, client_ip: IpAddr) {
+        RESPONSE_STATE.with(|state| {
+            state
+                .borrow_mut()
+                .entry(self.native as usize)
+                .or_default()
+                .client_ip = Some(client_ip)
+        });
+    }
+
+    fn resolved_client_ip(&self) -> Option<IpAddr> {
+        RESPONSE_STATE.with(|state| {
+            let state = state.borrow();
+            state.get(&(self.native as usize))?.client_ip
+        })
+    }
+
+    /// The client address found by `Application::resolve_client_ip`, or the address of the
+    /// TCP peer if there is no resolver.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.resolved_client_ip()
+            .or_else(|| self.get_remote_address())
+    }
+
+    // Writing anything but the status makes uWS send a 200, so the deferred headers go first
+    fn flush_deferred_headers(&self) {
+        mark_started(self.native);
+        if let Some(headers) = self.take_deferred_headers() {
+            for (key, value) in headers {
+                self.write_header_unchecked(&key, &value);
+            }
+        }
+    }
+
+    /// Like `write_header`, an invalid name is not written and panics in debug builds.
     pub fn write_header_int(&self, key: &str, value: u64) {
+        debug_assert!(is_valid_header_name(key), "Invalid header name {key:?}");
+        if !is_valid_header_name(key) {
+            return;
+        }
         let key_len = key.len();
+        self.flush_deferred_headers();
         let key_ptr = key.as_ptr() as *const c_char;
         unsafe {
             uws_res_write_header_int(SSL as c_int, self.native, key_ptr, key_len, value);
@@ -246,9 +392,11 @@ impl<const SSL: bool> HttpResponseStruct<SSL> {
     }
 
     pub fn end_without_body(&self, close_connection: bool) {
+        self.flush_deferred_headers();
         unsafe {
             uws_res_end_without_body(SSL as c_int, self.native, close_connection);
         }
+        self.complete_request();
     }
 
     pub fn get_write_offset(&self) -> u64 {
@@ -265,17 +413,71 @@ impl<const SSL: bool> HttpResponseStruct<SSL> {
         unsafe { uws_res_has_responded(SSL as c_int, self.native) }
     }
 
-    pub fn get_remote_address(&self) -> &str {
-        unsafe { read_str_from_with_ssl::<SSL, uws_res_t>(self.native, uws_res_get_remote_address) }
+    pub fn close(&self) {
+        unsafe {
+            us_socket_close(SSL as c_int, self.native as *mut us_socket_t, 0, null_mut());
+        }
     }
 
-    pub fn get_remote_address_as_text(&self) -> &str {
-        unsafe {
-            read_str_from_with_ssl::<SSL, uws_res_t>(
-                self.native,
-                uws_res_get_remote_address_as_text,
-            )
+    /// Streams exactly `total_len` bytes from the reader as the response body.
+    ///
+    /// Partial writes are retried from `on_writable`, the stream stops once the client aborts.
+    /// Takes over `on_writable` and `on_aborted` of this response. If the reader ends early or
+    /// fails, the connection is closed since the promised length can't be met anymore.
+    pub fn stream(&self, total_len: u64, reader: impl Read + 'static) {
+        if total_len == 0 {
+            self.end(None, false);
+            return;
+        }
+        start_body_stream(BodyStream::new(self, Some(total_len), reader));
+    }
+
+    /// Like `stream` but for bodies of unknown length, sent with chunked transfer encoding.
+    pub fn stream_chunked(&self, reader: impl Read + 'static) {
+        start_body_stream(BodyStream::new(self, None, reader));
+    }
+
+    pub fn chunked_writer(&self) -> ChunkedWriter<SSL> {
+        ChunkedWriter::new(self)
+    }
+
+    /// The address of the TCP peer, or the source address of the PROXY protocol header if the
+    /// connection came through a listen socket with `ListenOptions::proxy_protocol`.
+    pub fn get_remote_address(&self) -> Option<IpAddr> {
+        match source_address::<SSL>(self.native) {
+            Some(source) => Some(source.ip),
+            None => self.peer_address(),
+        }
+    }
+
+    pub(crate) fn peer_address(&self) -> Option<IpAddr> {
+        let mut buf: *const c_char = null();
+        let len = unsafe { uws_res_get_remote_address(SSL as c_int, self.native, &mut buf) };
+        ip_from_bytes(unsafe { read_buf_from_ptr(buf, len) })
+    }
+
+    /// The port of the TCP peer, or the source port of the PROXY protocol header. None for v2
+    /// headers, uWS parses those and keeps their port to itself.
+    pub fn get_remote_port(&self) -> Option<u16> {
+        if let Some(source) = source_address::<SSL>(self.native) {
+            return source.port;
         }
+        let port = unsafe { us_socket_remote_port(SSL as c_int, self.native as *mut us_socket_t) };
+        u16::try_from(port).ok()
+    }
+
+    pub fn get_remote_socket_addr(&self) -> Option<SocketAddr> {
+        Some(SocketAddr::new(
+            self.get_remote_address()?,
+            self.get_remote_port()?,
+        ))
+    }
+
+    /// The remote address like `203.0.113.7` or `2001:db8::1`, empty if it is unknown.
+    pub fn get_remote_address_as_text(&self) -> String {
+        self.get_remote_address()
+            .map(|ip| ip.to_string())
+            .unwrap_or_default()
     }
 
     pub fn upgrade<T>(
@@ -298,6 +500,20 @@ impl<const SSL: bool> HttpResponseStruct<SSL> {
         let extensions_ptr = ws_extensions.map(|ext| ext.as_ptr()).unwrap_or(null());
         let extensions_len = ws_extensions.map(|ext| ext.len()).unwrap_or(0);
 
+        // uWS only writes its 101 if no status was written, deferred headers need it first
+        if let Some(headers) = self.take_deferred_headers() {
+            self.write_status_unchecked("101 Switching Protocols");
+            for (key, value) in headers {
+                self.write_header_unchecked(&key, &value);
+            }
+        }
+        self.complete_request();
+        stop_header_timeout(self.native);
+        let _upgrading = UpgradingAddresses::set(UpgradedAddresses {
+            client_ip: self.resolved_client_ip(),
+            proxied_address: take_source_address::<SSL>(self.native),
+        });
+
         unsafe {
             uws_res_upgrade(
                 SSL as c_int,
@@ -315,6 +531,169 @@ impl<const SSL: bool> HttpResponseStruct<SSL> {
     }
 }
 
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+struct BodyStream<R: Read, const SSL: bool> {
+    res: HttpResponseStruct<SSL>,
+    reader: R,
+    // None streams with chunked transfer encoding
+    total_size: Option<u64>,
+    chunk: Vec<u8>,
+    chunk_offset: u64,
+    // Set while the stream runs uWS calls, one of those may close the socket
+    // and fire on_aborted, which must not free the stream under our feet
+    busy: bool,
+    finished: bool,
+}
+
+impl<R: Read, const SSL: bool> BodyStream<R, SSL> {
+    fn new(res: &HttpResponseStruct<SSL>, total_size: Option<u64>, reader: R) -> Self {
+        BodyStream {
+            res: HttpResponseStruct::<SSL>::new(res.native),
+            reader,
+            total_size,
+            chunk: Vec::new(),
+            chunk_offset: 0,
+            busy: false,
+            finished: false,
+        }
+    }
+
+    fn read_chunk(&mut self, max_len: u64) -> io::Result<usize> {
+        let mut buf = vec![0; max_len.min(STREAM_CHUNK_SIZE as u64) as usize];
+        loop {
+            match self.reader.read(&mut buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+                Ok(read) => {
+                    buf.truncate(read);
+                    self.chunk = buf;
+                    return Ok(read);
+                }
+            }
+        }
+    }
+
+    // Writes chunks until the response is done or the socket is backpressured.
+    // Returns false on backpressure.
+    fn pump(&mut self) -> bool {
+        self.busy = true;
+        let ok = match self.total_size {
+            Some(total_size) => self.pump_sized(total_size),
+            None => self.pump_chunked(),
+        };
+        self.busy = false;
+        ok
+    }
+
+    fn pump_sized(&mut self, total_size: u64) -> bool {
+        loop {
+            if self.chunk.is_empty() {
+                let remaining = total_size - self.res.get_write_offset();
+                if let Ok(0) | Err(_) = self.read_chunk(remaining) {
+                    // The reader can't deliver the promised length, the only honest option is to drop the connection
+                    self.finished = true;
+                    self.res.close();
+                    return true;
+                }
+            }
+
+            self.chunk_offset = self.res.get_write_offset();
+            let result = self.res.try_end(Some(&self.chunk), total_size, false);
+            if result.has_responded {
+                self.finished = true;
+                return true;
+            }
+            if !result.ok {
+                return false;
+            }
+            self.chunk.clear();
+        }
+    }
+
+    // uWS buffers whatever `write` doesn't get out, so there is nothing to retry here,
+    // reading just stops until the socket drains
+    fn pump_chunked(&mut self) -> bool {
+        loop {
+            match self.read_chunk(STREAM_CHUNK_SIZE as u64) {
+                Ok(0) => {
+                    self.finished = true;
+                    self.res.end(None, false);
+                    return true;
+                }
+                Ok(_) => {
+                    if !self.res.write(&self.chunk) {
+                        return false;
+                    }
+                }
+                Err(_) => {
+                    // A chunked body can't signal errors, closing without the last chunk tells the client it is truncated
+                    self.finished = true;
+                    self.res.close();
+                    return true;
+                }
+            }
+        }
+    }
+
+    fn on_writable(&mut self, offset: u64) -> bool {
+        if self.total_size.is_some() {
+            let written = (offset - self.chunk_offset) as usize;
+            self.chunk.drain(..written.min(self.chunk.len()));
+        }
+        self.pump()
+    }
+}
+
+fn start_body_stream<R: Read + 'static, const SSL: bool>(stream: BodyStream<R, SSL>) {
+    let native = stream.res.native;
+    let stream = Box::into_raw(Box::new(stream));
+
+    unsafe {
+        (*stream).pump();
+        if (*stream).finished {
+            drop(Box::from_raw(stream));
+            return;
+        }
+
+        uws_res_on_writable(
+            SSL as c_int,
+            native,
+            Some(body_stream_on_writable::<R, SSL>),
+            stream as *mut c_void,
+        );
+        set_on_aborted::<SSL>(
+            native,
+            body_stream_on_aborted::<R, SSL>,
+            stream as *mut c_void,
+        );
+    }
+}
+
+unsafe extern "C" fn body_stream_on_writable<R: Read, const SSL: bool>(
+    _: *mut uws_res_t,
+    offset: u64,
+    user_data: *mut c_void,
+) -> bool {
+    let stream = user_data as *mut BodyStream<R, SSL>;
+    let ok = (*stream).on_writable(offset);
+    if (*stream).finished {
+        drop(Box::from_raw(stream));
+    }
+    ok
+}
+
+unsafe extern "C" fn body_stream_on_aborted<R: Read, const SSL: bool>(
+    _: *mut uws_res_t,
+    user_data: *mut c_void,
+) {
+    let stream = user_data as *mut BodyStream<R, SSL>;
+    (*stream).finished = true;
+    if !(*stream).busy {
+        drop(Box::from_raw(stream));
+    }
+}
+
 unsafe extern "C" fn on_abort(_res: *mut uws_res_t, user_data: *mut c_void) {
     let http_response = Box::from_raw(user_data as *mut HttpResponseStruct<false>);
 
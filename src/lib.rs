@@ -1,10 +1,34 @@
 pub mod app;
 pub mod app_close;
+pub mod chunked_writer;
+pub mod client_ip;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod connection_limits;
+pub mod cookie;
+pub mod cors;
+#[cfg(feature = "decompression")]
+pub mod decompression;
+pub mod form;
+pub mod header;
+#[cfg(feature = "http")]
+mod http_interop;
 pub mod http_request;
 pub mod http_response;
 pub mod listen_socket;
+pub mod multi_threaded_app;
+mod proxy_protocol;
+pub mod rate_limit;
+pub mod request_timeout;
+pub mod sse;
+mod static_files;
+pub mod status_code;
+pub mod topic_bus;
+#[cfg(feature = "tower")]
+mod tower_interop;
 pub mod us_socket_context_options;
 mod utils;
+mod uv;
 pub mod uws_loop;
 pub mod websocket;
 pub mod websocket_behavior;
@@ -1,5 +1,7 @@
 use libuwebsockets_sys::{us_listen_socket_close, us_listen_socket_t};
 
+use crate::proxy_protocol::forget_listen_socket;
+
 #[derive(Clone, Copy, Debug)]
 pub struct ListenSocket {
     pub(crate) listen_socket_ptr: *mut us_listen_socket_t,
@@ -7,6 +9,30 @@ pub struct ListenSocket {
 unsafe impl Send for ListenSocket {}
 unsafe impl Sync for ListenSocket {}
 
+/// Options of `Application::listen_with_options`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ListenOptions {
+    pub(crate) proxy_protocol: bool,
+}
+
+impl ListenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connections accepted by this listen socket must start with a PROXY protocol v1 or v2
+    /// header, its source address and port become the remote address of responses and
+    /// WebSockets. Connections without a valid header within 10 seconds are closed. v2 headers
+    /// are parsed by uWS, which doesn't hand out their source port.
+    ///
+    /// Only enable this if every connection comes through a load balancer, the header is
+    /// taken at face value.
+    pub fn proxy_protocol(mut self, proxy_protocol: bool) -> Self {
+        self.proxy_protocol = proxy_protocol;
+        self
+    }
+}
+
 #[cfg(feature = "native-access")]
 impl ListenSocket {
     pub fn get_native(&self) -> *mut us_listen_socket_t {
@@ -15,6 +41,7 @@ impl ListenSocket {
 }
 
 pub fn listen_socket_close<const SSL: bool>(listen_socket: ListenSocket) {
+    forget_listen_socket(listen_socket);
     unsafe {
         us_listen_socket_close(SSL.into(), listen_socket.listen_socket_ptr);
     }
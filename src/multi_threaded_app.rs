@@ -0,0 +1,161 @@
+use std::cell::Cell;
+use std::io;
+use std::rc::Rc;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::app::{AppLiveness, Application, NativeApp};
+use crate::app_close::app_close;
+use crate::listen_socket::ListenSocket;
+use crate::us_socket_context_options::UsSocketContextOptions;
+use crate::uws_loop::{get_loop, loop_defer, UwsLoop};
+
+pub type MultiThreadedApp = MultiThreadedApplication<false>;
+pub type MultiThreadedSSLApp = MultiThreadedApplication<true>;
+
+struct Worker {
+    native: NativeApp,
+    uws_loop: UwsLoop,
+    liveness: AppLiveness,
+    handle: JoinHandle<()>,
+}
+
+/// Runs one `Application` per thread, all of them listening on the same port.
+///
+/// uSockets sets `SO_REUSEPORT` on listen sockets, so the kernel balances incoming
+/// connections between the threads. Routes are built on every thread by the same closure,
+/// which lets handlers stay non-`Send`.
+///
+/// Creating it fails if any thread can't listen on the port, the other threads are shut
+/// down then. Dropping it shuts every thread down and waits for them.
+pub struct MultiThreadedApplication<const SSL: bool> {
+    workers: Vec<Worker>,
+}
+
+impl<const SSL: bool> MultiThreadedApplication<SSL> {
+    pub fn new<F>(
+        threads: usize,
+        port: i32,
+        socket_config: UsSocketContextOptions,
+        build_routes: F,
+    ) -> io::Result<Self>
+    where
+        F: Fn(&mut Application<SSL>) + Send + Sync + 'static,
+    {
+        let build_routes = Arc::new(build_routes);
+        let (sender, receiver) = channel();
+
+        let handles: Vec<JoinHandle<()>> = (0..threads.max(1))
+            .map(|index| {
+                let build_routes = build_routes.clone();
+                let socket_config = socket_config.clone();
+                let sender = sender.clone();
+
+                thread::Builder::new()
+                    .name(format!("uws-worker-{index}"))
+                    .spawn(move || {
+                        let mut app = Application::<SSL>::new(socket_config);
+                        build_routes(&mut app);
+                        let listening = Rc::new(Cell::new(false));
+                        let listened = listening.clone();
+                        app.listen(
+                            port,
+                            Some(move |listen_socket: ListenSocket| {
+                                listened.set(!listen_socket.listen_socket_ptr.is_null())
+                            }),
+                        );
+
+                        let started = listening
+                            .get()
+                            .then(|| (app.native, get_loop(), app.liveness.clone()));
+                        sender
+                            .send((index, started))
+                            .expect("MultiThreadedApplication was dropped during startup");
+                        drop(sender);
+
+                        if listening.get() {
+                            app.run();
+                        }
+                    })
+                    .expect("Can't spawn uWS worker thread")
+            })
+            .collect();
+        drop(sender);
+
+        // Threads that panicked while building their routes never report
+        let mut started: Vec<Option<(NativeApp, UwsLoop, AppLiveness)>> = vec![None; handles.len()];
+        for (index, worker) in receiver {
+            started[index] = worker;
+        }
+        let failed = started.iter().position(Option::is_none);
+
+        let mut workers = Vec::new();
+        let mut stopped = Vec::new();
+        for (handle, started) in handles.into_iter().zip(started) {
+            match started {
+                Some((native, uws_loop, liveness)) => workers.push(Worker {
+                    native,
+                    uws_loop,
+                    liveness,
+                    handle,
+                }),
+                None => stopped.push(handle),
+            }
+        }
+        let app = MultiThreadedApplication { workers };
+
+        let Some(failed) = failed else {
+            return Ok(app);
+        };
+        app.shutdown();
+        let _ = app.join();
+        for handle in stopped {
+            let _ = handle.join();
+        }
+        Err(io::Error::other(format!(
+            "uWS worker {failed} didn't start listening on port {port}"
+        )))
+    }
+
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn loops(&self) -> Vec<UwsLoop> {
+        self.workers.iter().map(|worker| worker.uws_loop).collect()
+    }
+
+    /// Closes every app on its own loop thread, so that each `run` returns. Apps that are
+    /// closed already are skipped.
+    pub fn shutdown(&self) {
+        for worker in &self.workers {
+            let native = worker.native;
+            worker.liveness.while_alive(|| {
+                loop_defer(worker.uws_loop, move || app_close::<SSL>(native));
+            });
+        }
+    }
+
+    pub fn join(mut self) -> thread::Result<()> {
+        let mut result = Ok(());
+        for worker in std::mem::take(&mut self.workers) {
+            if let Err(e) = worker.handle.join() {
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+impl<const SSL: bool> Drop for MultiThreadedApplication<SSL> {
+    fn drop(&mut self) {
+        self.shutdown();
+        for worker in self.workers.drain(..) {
+            // A worker can't wait for itself
+            if worker.handle.thread().id() != thread::current().id() {
+                let _ = worker.handle.join();
+            }
+        }
+    }
+}
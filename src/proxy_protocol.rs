@@ -0,0 +1,421 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, c_void};
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::ptr::{null, null_mut};
+use std::time::Duration;
+
+use libuwebsockets_sys::{
+    us_poll_fd, us_poll_t, us_socket_close, us_socket_is_closed, us_socket_local_port, us_socket_t,
+    uws_app_t, uws_filter, uws_res_pause, uws_res_resume, uws_res_t,
+};
+
+use crate::listen_socket::ListenSocket;
+use crate::utils::{ip_from_bytes, read_buf_from_ptr};
+use crate::uws_loop::{get_loop, poll_fd, set_timeout, FdPoll, PollEvents, Timer};
+
+// uWS is built with UWS_WITH_PROXY, the bindings are generated without it
+extern "C" {
+    fn uws_res_get_proxied_remote_address(
+        ssl: c_int,
+        res: *mut uws_res_t,
+        dest: *mut *const c_char,
+    ) -> usize;
+}
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+// `PROXY UNKNOWN\r\n`, no header is shorter
+const MIN_LEN: usize = 15;
+// v2 headers start with `\r\n\r\n`, uWS parses those itself
+const V2_START: u8 = b'\r';
+// Same as the idle timeout uWS gives connections to send their request
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ProxyHeader {
+    Incomplete,
+    Invalid,
+    /// `UNKNOWN` headers carry no source, the TCP peer stays the remote address.
+    Complete {
+        len: usize,
+        source: Option<SocketAddr>,
+    },
+}
+
+/// Parses a PROXY protocol v1 header at the start of `buf`.
+pub(crate) fn parse_header(buf: &[u8]) -> ProxyHeader {
+    let len = buf.len().min(V1_PREFIX.len());
+    if buf[..len] != V1_PREFIX[..len] {
+        return ProxyHeader::Invalid;
+    }
+    if buf.len() < MIN_LEN {
+        return ProxyHeader::Incomplete;
+    }
+
+    // `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`
+    let searched = &buf[..buf.len().min(V1_MAX_LEN)];
+    let Some(end) = searched.windows(2).position(|window| window == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            return ProxyHeader::Invalid;
+        }
+        return ProxyHeader::Incomplete;
+    };
+    let len = end + 2;
+    let Ok(line) = std::str::from_utf8(&buf[..end]) else {
+        return ProxyHeader::Invalid;
+    };
+    let fields: Vec<&str> = line.split(' ').collect();
+    let source = match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", protocol, source, destination, source_port, destination_port] => {
+            let addresses = match protocol {
+                "TCP4" => source
+                    .parse::<Ipv4Addr>()
+                    .ok()
+                    .map(IpAddr::V4)
+                    .zip(destination.parse::<Ipv4Addr>().ok().map(IpAddr::V4)),
+                "TCP6" => source
+                    .parse::<Ipv6Addr>()
+                    .ok()
+                    .map(IpAddr::V6)
+                    .zip(destination.parse::<Ipv6Addr>().ok().map(IpAddr::V6)),
+                _ => None,
+            };
+            let ports = source_port
+                .parse::<u16>()
+                .ok()
+                .zip(destination_port.parse::<u16>().ok());
+            let (Some((source, _)), Some((source_port, _))) = (addresses, ports) else {
+                return ProxyHeader::Invalid;
+            };
+            Some(SocketAddr::new(source, source_port))
+        }
+        _ => return ProxyHeader::Invalid,
+    };
+    ProxyHeader::Complete { len, source }
+}
+
+struct Listener {
+    listen_socket: usize,
+    port: u16,
+}
+
+// A connection whose header didn't fully arrive yet
+struct PendingHeader {
+    // A duplicate of the socket's fd, uWS doesn't read from it while the socket is paused
+    stream: TcpStream,
+    poll: FdPoll,
+    timer: Timer,
+    received: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+enum Source {
+    V1(SocketAddr),
+    // Parsed by uWS, which is asked for the address
+    V2,
+}
+
+enum Progress {
+    Waiting,
+    Done(Option<Source>),
+    Failed,
+}
+
+impl PendingHeader {
+    // Peeks at what arrived and takes no more than the header off the socket, whatever follows
+    // is left to uWS
+    fn read(&mut self) -> Progress {
+        let mut buf = [0; V1_MAX_LEN];
+        let room = V1_MAX_LEN - self.received.len();
+        let peeked = match self.stream.peek(&mut buf[..room]) {
+            Ok(0) => return Progress::Failed,
+            Ok(peeked) => peeked,
+            Err(error) => match error.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => return Progress::Waiting,
+                _ => return Progress::Failed,
+            },
+        };
+        if self.received.is_empty() && buf[0] == V2_START {
+            return Progress::Done(Some(Source::V2));
+        }
+
+        let received = self.received.len();
+        self.received.extend_from_slice(&buf[..peeked]);
+        let progress = match parse_header(&self.received) {
+            ProxyHeader::Incomplete => Progress::Waiting,
+            ProxyHeader::Complete { len, source } => {
+                self.received.truncate(len);
+                Progress::Done(source.map(Source::V1))
+            }
+            ProxyHeader::Invalid => return Progress::Failed,
+        };
+        let taken = self.received.len() - received;
+        if self.stream.read_exact(&mut buf[..taken]).is_err() {
+            return Progress::Failed;
+        }
+        progress
+    }
+}
+
+impl Drop for PendingHeader {
+    fn drop(&mut self) {
+        self.poll.stop();
+        self.timer.cancel();
+    }
+}
+
+thread_local! {
+    // Listen sockets expecting headers, by native app
+    static LISTENERS: RefCell<HashMap<usize, Vec<Listener>>> = RefCell::new(HashMap::new());
+    // By native socket
+    static PENDING: RefCell<HashMap<usize, PendingHeader>> = RefCell::new(HashMap::new());
+    static SOURCES: RefCell<HashMap<usize, Source>> = RefCell::new(HashMap::new());
+}
+
+/// Makes connections accepted by `listen_socket` start with a header.
+pub(crate) fn expect_headers<const SSL: bool>(app: *mut uws_app_t, listen_socket: ListenSocket) {
+    let socket = listen_socket.listen_socket_ptr as *mut us_socket_t;
+    let Ok(port) = u16::try_from(unsafe { us_socket_local_port(SSL as c_int, socket) }) else {
+        return;
+    };
+    let installed = LISTENERS.with(|listeners| {
+        let mut listeners = listeners.borrow_mut();
+        let installed = listeners.contains_key(&(app as usize));
+        listeners.entry(app as usize).or_default().push(Listener {
+            listen_socket: listen_socket.listen_socket_ptr as usize,
+            port,
+        });
+        installed
+    });
+    if !installed {
+        unsafe {
+            uws_filter(
+                SSL as c_int,
+                app,
+                Some(proxy_filter::<SSL>),
+                app as *mut c_void,
+            )
+        };
+    }
+}
+
+pub(crate) fn forget_listen_socket(listen_socket: ListenSocket) {
+    let listen_socket = listen_socket.listen_socket_ptr as usize;
+    LISTENERS.with(|listeners| {
+        for app_listeners in listeners.borrow_mut().values_mut() {
+            app_listeners.retain(|listener| listener.listen_socket != listen_socket);
+        }
+    });
+}
+
+// Its filter stays installed but finds nothing to do anymore
+pub(crate) fn forget_app(app: *mut uws_app_t) {
+    LISTENERS.with(|listeners| listeners.borrow_mut().remove(&(app as usize)));
+}
+
+/// The source of the header a connection started with. uWS doesn't hand out the source port of
+/// v2 headers, only their address.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct ProxiedSource {
+    pub(crate) ip: IpAddr,
+    pub(crate) port: Option<u16>,
+}
+
+pub(crate) fn source_address<const SSL: bool>(socket: *mut uws_res_t) -> Option<ProxiedSource> {
+    let source = SOURCES.with(|sources| {
+        let sources = sources.borrow();
+        if sources.is_empty() {
+            return None;
+        }
+        sources.get(&(socket as usize)).copied()
+    })?;
+    resolve_source::<SSL>(socket, source)
+}
+
+// Upgraded sockets leave the HTTP context without a close event
+pub(crate) fn take_source_address<const SSL: bool>(
+    socket: *mut uws_res_t,
+) -> Option<ProxiedSource> {
+    let source = forget_source(socket)?;
+    resolve_source::<SSL>(socket, source)
+}
+
+fn forget_source(socket: *mut uws_res_t) -> Option<Source> {
+    SOURCES.with(|sources| {
+        let mut sources = sources.borrow_mut();
+        if sources.is_empty() {
+            return None;
+        }
+        sources.remove(&(socket as usize))
+    })
+}
+
+fn resolve_source<const SSL: bool>(
+    socket: *mut uws_res_t,
+    source: Source,
+) -> Option<ProxiedSource> {
+    match source {
+        Source::V1(address) => Some(ProxiedSource {
+            ip: address.ip(),
+            port: Some(address.port()),
+        }),
+        Source::V2 => {
+            let mut buf: *const c_char = null();
+            let len = unsafe { uws_res_get_proxied_remote_address(SSL as c_int, socket, &mut buf) };
+            // Empty for `LOCAL` headers
+            if len == 0 {
+                return None;
+            }
+            let ip = ip_from_bytes(unsafe { read_buf_from_ptr(buf, len) })?;
+            Some(ProxiedSource { ip, port: None })
+        }
+    }
+}
+
+unsafe extern "C" fn proxy_filter<const SSL: bool>(
+    res: *mut uws_res_t,
+    event: c_int,
+    user_data: *mut c_void,
+) {
+    let socket = res as *mut us_socket_t;
+    if event < 0 {
+        PENDING.with(|pending| pending.borrow_mut().remove(&(socket as usize)));
+        forget_source(res);
+        return;
+    }
+
+    // Earlier filters may have turned the connection away already
+    if us_socket_is_closed(SSL as c_int, socket) != 0 {
+        return;
+    }
+    let port = us_socket_local_port(SSL as c_int, socket);
+    let expects_header = LISTENERS.with(|listeners| {
+        listeners
+            .borrow()
+            .get(&(user_data as usize))
+            .is_some_and(|listeners| {
+                listeners
+                    .iter()
+                    .any(|listener| c_int::from(listener.port) == port)
+            })
+    });
+    if expects_header {
+        await_header::<SSL>(socket);
+    }
+}
+
+unsafe fn await_header<const SSL: bool>(socket: *mut us_socket_t) {
+    // Sockets start with their poll
+    let fd = BorrowedFd::borrow_raw(us_poll_fd(socket as *mut us_poll_t));
+    let Ok(fd) = fd.try_clone_to_owned() else {
+        us_socket_close(SSL as c_int, socket, 0, null_mut());
+        return;
+    };
+    let stream = TcpStream::from(fd);
+
+    let key = socket as usize;
+    let poll = poll_fd(
+        get_loop(),
+        stream.as_raw_fd(),
+        PollEvents::readable(),
+        move |_, events| match events {
+            Ok(_) => read_header::<SSL>(key),
+            Err(_) => reject::<SSL>(key),
+        },
+    );
+    let Ok(poll) = poll else {
+        us_socket_close(SSL as c_int, socket, 0, null_mut());
+        return;
+    };
+    let timer = set_timeout(get_loop(), HEADER_TIMEOUT, move || reject::<SSL>(key));
+
+    uws_res_pause(SSL as c_int, socket as *mut uws_res_t);
+    let pending = PendingHeader {
+        stream,
+        poll,
+        timer,
+        received: Vec::new(),
+    };
+    PENDING.with(|all| all.borrow_mut().insert(key, pending));
+}
+
+fn read_header<const SSL: bool>(key: usize) {
+    let progress =
+        PENDING.with(|pending| pending.borrow_mut().get_mut(&key).map(PendingHeader::read));
+    match progress {
+        None | Some(Progress::Waiting) => {}
+        Some(Progress::Failed) => reject::<SSL>(key),
+        Some(Progress::Done(source)) => {
+            PENDING.with(|pending| pending.borrow_mut().remove(&key));
+            if let Some(source) = source {
+                SOURCES.with(|sources| sources.borrow_mut().insert(key, source));
+            }
+            unsafe { uws_res_resume(SSL as c_int, key as *mut uws_res_t) };
+        }
+    }
+}
+
+// Closing calls the filter, which drops what is pending
+fn reject<const SSL: bool>(key: usize) {
+    unsafe { us_socket_close(SSL as c_int, key as *mut us_socket_t, 0, null_mut()) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete(len: usize, source: &str) -> ProxyHeader {
+        ProxyHeader::Complete {
+            len,
+            source: Some(source.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn parses_v1() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(parse_header(header), complete(45, "192.0.2.1:56324"));
+
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        assert_eq!(parse_header(header), complete(46, "[2001:db8::1]:56324"));
+
+        assert_eq!(
+            parse_header(b"PROXY UNKNOWN\r\n"),
+            ProxyHeader::Complete {
+                len: 15,
+                source: None
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_v1() {
+        for header in [
+            &b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 443\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+            &[b"PROXY UNKNOWN ".as_slice(), &[b'x'; 100]].concat(),
+        ] {
+            assert_eq!(parse_header(header), ProxyHeader::Invalid);
+        }
+    }
+
+    #[test]
+    fn waits_for_the_whole_v1_header() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+        for len in 0..header.len() {
+            assert_eq!(parse_header(&header[..len]), ProxyHeader::Incomplete);
+        }
+    }
+
+    #[test]
+    fn rejects_connections_without_header() {
+        assert_eq!(parse_header(b"GET / HTTP/1.1\r\n"), ProxyHeader::Invalid);
+        assert_eq!(parse_header(b"PRO"), ProxyHeader::Incomplete);
+    }
+}
@@ -0,0 +1,301 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv6Addr};
+use std::ops::ControlFlow;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponseStruct;
+use crate::status_code::StatusCode;
+use crate::websocket::WebSocketStruct;
+use crate::websocket_behavior::WebSocketBehavior;
+
+/// WebSocket close code for messages that violate the server's policy.
+pub const POLICY_VIOLATION: i32 = 1008;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    per_second: f64,
+    burst: u32,
+}
+
+impl RateLimit {
+    /// Allows `burst` hits at once, refilled at `per_second`.
+    ///
+    /// Panics unless both are positive.
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        assert!(per_second > 0.0, "Rate must be positive");
+        assert!(burst > 0, "Burst must be positive");
+        RateLimit { per_second, burst }
+    }
+
+    pub fn per_second(per_second: u32) -> Self {
+        RateLimit::new(per_second as f64, per_second)
+    }
+
+    pub fn per_minute(per_minute: u32) -> Self {
+        RateLimit::new(per_minute as f64 / 60.0, per_minute)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(limit: &RateLimit) -> Self {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or tells how long until the next one is available.
+    pub fn try_acquire(&mut self, limit: &RateLimit) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / limit.per_second,
+            ))
+        }
+    }
+}
+
+// Least recently used entries are evicted once `capacity` is reached
+struct Lru<K, V> {
+    entries: HashMap<K, (V, u64)>,
+    order: BTreeMap<u64, K>,
+    next_stamp: u64,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+    fn new(capacity: usize) -> Self {
+        Lru {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_stamp: 0,
+            capacity: capacity.max(1),
+        }
+    }
+
+    fn get_or_insert_with(&mut self, key: K, value: impl FnOnce() -> V) -> &mut V {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+
+        if let Some((_, old_stamp)) = self.entries.get(&key) {
+            self.order.remove(old_stamp);
+        } else if self.entries.len() >= self.capacity {
+            if let Some((_, evicted)) = self.order.pop_first() {
+                self.entries.remove(&evicted);
+            }
+        }
+        self.order.insert(stamp, key.clone());
+
+        let entry = self.entries.entry(key).or_insert_with(|| (value(), stamp));
+        entry.1 = stamp;
+        &mut entry.0
+    }
+}
+
+struct RateLimiterState {
+    limit: RateLimit,
+    clients: RefCell<Lru<IpAddr, TokenBucket>>,
+}
+
+/// Token bucket per client IP, shared by every route and upgrade it is attached to.
+/// Clients are told apart by `HttpResponseStruct::client_ip`. IPv6 clients share the bucket
+/// of their /64 network, a single host usually gets a whole one to pick addresses from.
+///
+/// At most `max_clients` buckets are kept, the least recently seen clients are forgotten
+/// first and start over with a full bucket.
+#[derive(Clone)]
+pub struct RateLimiter {
+    state: Rc<RateLimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit, max_clients: usize) -> Self {
+        RateLimiter {
+            state: Rc::new(RateLimiterState {
+                limit,
+                clients: RefCell::new(Lru::new(max_clients)),
+            }),
+        }
+    }
+
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let limit = &self.state.limit;
+        self.state
+            .clients
+            .borrow_mut()
+            .get_or_insert_with(client_key(ip), || TokenBucket::new(limit))
+            .try_acquire(limit)
+    }
+
+    /// Answers with 429 and `Retry-After` once the client is over the limit, fits
+    /// `Application::before_request`.
+    pub fn limit_request<const SSL: bool>(&self, res: &HttpResponseStruct<SSL>) -> ControlFlow<()> {
+        let Some(ip) = res.client_ip() else {
+            return ControlFlow::Continue(());
+        };
+        match self.check(ip) {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(wait) => {
+                let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+                res.write_status_code(StatusCode::TOO_MANY_REQUESTS);
+                res.write_header_int("Retry-After", retry_after);
+                res.end(None, false);
+                ControlFlow::Break(())
+            }
+        }
+    }
+
+    /// Limits a single route.
+    pub fn wrap<const SSL: bool>(
+        &self,
+        handler: impl Fn(HttpResponseStruct<SSL>, HttpRequest) + Unpin + 'static,
+    ) -> impl Fn(HttpResponseStruct<SSL>, HttpRequest) + Unpin + 'static {
+        let limiter = self.clone();
+        move |res, req| {
+            if limiter.limit_request(&res).is_continue() {
+                handler(res, req);
+            }
+        }
+    }
+
+    /// Limits the upgrade requests of a WebSocket route, rejected clients get a 429.
+    pub fn limit_upgrades<const SSL: bool>(&self, behavior: &mut WebSocketBehavior<SSL>) {
+        let limiter = self.clone();
+        let upgrade = behavior.upgrade.take();
+        behavior.upgrade = Some(Box::new(move |res, req, context| {
+            if limiter.limit_request(&res).is_break() {
+                return;
+            }
+            match &upgrade {
+                Some(upgrade) => upgrade(res, req, context),
+                None => HttpResponseStruct::default_upgrade(res, req, context),
+            }
+        }));
+    }
+}
+
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(Ipv6Addr::from(ip.to_bits() & !u128::from(u64::MAX))),
+        },
+    }
+}
+
+struct MessageRateLimiterState {
+    limit: RateLimit,
+    // Keyed by the native WebSocket, entries are removed when it closes
+    connections: RefCell<HashMap<usize, TokenBucket>>,
+}
+
+/// Token bucket per WebSocket connection for incoming messages.
+///
+/// Connections going over the limit are closed with 1008 (policy violation).
+#[derive(Clone)]
+pub struct MessageRateLimiter {
+    state: Rc<MessageRateLimiterState>,
+}
+
+impl MessageRateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        MessageRateLimiter {
+            state: Rc::new(MessageRateLimiterState {
+                limit,
+                connections: RefCell::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Takes a token for one message, closes the connection and returns false if there is none.
+    pub fn check<const SSL: bool>(&self, ws: &WebSocketStruct<SSL>) -> bool {
+        let limit = &self.state.limit;
+        let allowed = self
+            .state
+            .connections
+            .borrow_mut()
+            .entry(ws.native as usize)
+            .or_insert_with(|| TokenBucket::new(limit))
+            .try_acquire(limit)
+            .is_ok();
+
+        if !allowed {
+            ws.end(POLICY_VIOLATION, Some("Message rate limit exceeded"));
+        }
+        allowed
+    }
+
+    /// Forgets the bucket of a closed connection, `apply_to` does this already.
+    pub fn remove<const SSL: bool>(&self, ws: &WebSocketStruct<SSL>) {
+        self.state
+            .connections
+            .borrow_mut()
+            .remove(&(ws.native as usize));
+    }
+
+    /// Checks every message before the `message` handler of the behavior sees it.
+    pub fn apply_to<const SSL: bool>(&self, behavior: &mut WebSocketBehavior<SSL>) {
+        let limiter = self.clone();
+        let message = behavior.message.take();
+        behavior.message = Some(Box::new(move |ws, data, opcode| {
+            if !limiter.check(&ws) {
+                return;
+            }
+            if let Some(message) = &message {
+                message(ws, data, opcode);
+            }
+        }));
+
+        let limiter = self.clone();
+        let close = behavior.close.take();
+        behavior.close = Some(Box::new(move |ws, code, reason| {
+            limiter.remove(&ws);
+            if let Some(close) = &close {
+                close(ws, code, reason);
+            }
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn keys_ipv6_clients_by_network() {
+        assert_eq!(client_key(ip("10.0.0.1")), ip("10.0.0.1"));
+        assert_eq!(client_key(ip("2001:db8:1:2:3:4:5:6")), ip("2001:db8:1:2::"));
+        assert_eq!(client_key(ip("::ffff:10.0.0.1")), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn limits_a_network_as_one_client() {
+        let limiter = RateLimiter::new(RateLimit::new(0.001, 1), 16);
+        assert!(limiter.check(ip("2001:db8::1")).is_ok());
+        assert!(limiter.check(ip("2001:db8::ffff:2")).is_err());
+        assert!(limiter.check(ip("2001:db8:0:1::1")).is_ok());
+    }
+}
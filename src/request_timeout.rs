@@ -0,0 +1,402 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, c_void};
+use std::ops::ControlFlow;
+use std::ptr::null_mut;
+use std::time::Duration;
+
+use libuwebsockets_sys::{
+    us_socket_close, us_socket_is_closed, us_socket_t, uws_app_t, uws_filter, uws_res_on_aborted,
+    uws_res_on_data, uws_res_t,
+};
+
+use crate::app::Application;
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponseStruct;
+use crate::status_code::StatusCode;
+use crate::uws_loop::{get_loop, set_timeout, Timer};
+
+pub(crate) type AbortCallback = unsafe extern "C" fn(*mut uws_res_t, *mut c_void);
+pub(crate) type DataCallback =
+    unsafe extern "C" fn(*mut uws_res_t, *const c_char, usize, bool, *mut c_void);
+
+/// Bounds how long requests may take, installed for every route with
+/// `Application::request_timeouts` or for a single one with `wrap`.
+///
+/// `headers` counts from the moment a connection is accepted or finished its previous
+/// response, a connection that didn't deliver the headers of its next request by then is
+/// closed. It can only be set for the whole application, `wrap` ignores it. uWS still closes
+/// connections that take longer than its own limit of about 10 seconds.
+///
+/// The other timeouts count from the moment the request is routed. A body that doesn't
+/// receive anything for `body` is answered with 408, a response the handler didn't end
+/// within `response` with 503, both closing the connection. Responses that already started
+/// are cut off by closing the connection. The `on_aborted` handlers of the response run
+/// before that, just like when the client goes away.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestTimeouts {
+    headers: Option<Duration>,
+    body: Option<Duration>,
+    response: Option<Duration>,
+}
+
+impl RequestTimeouts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn headers(mut self, timeout: Duration) -> Self {
+        self.headers = Some(timeout);
+        self
+    }
+
+    /// Applies only to requests whose handler reads the body with `on_data`, every chunk
+    /// received starts it over.
+    pub fn body(mut self, timeout: Duration) -> Self {
+        self.body = Some(timeout);
+        self
+    }
+
+    pub fn response(mut self, timeout: Duration) -> Self {
+        self.response = Some(timeout);
+        self
+    }
+
+    /// Starts the timeouts for the request of `res`, replacing any that were started before.
+    pub fn apply<const SSL: bool>(&self, res: &HttpResponseStruct<SSL>) {
+        let native = res.native;
+        disarm::<SSL>(native);
+        if self.body.is_none() && self.response.is_none() {
+            return;
+        }
+
+        let key = native as usize;
+        let start = |timeout: Option<Duration>, kind: TimeoutKind| {
+            timeout
+                .map(|timeout| set_timeout(get_loop(), timeout, move || expire::<SSL>(key, kind)))
+        };
+        let pending = PendingRequest {
+            body_timeout: self.body,
+            body_timer: start(self.body, TimeoutKind::Body),
+            response_timer: start(self.response, TimeoutKind::Response),
+            started: false,
+            on_aborted: None,
+            on_data: None,
+        };
+        PENDING.with(|pending_requests| pending_requests.borrow_mut().insert(key, pending));
+    }
+
+    /// Times a single route, overriding the timeouts of the application.
+    pub fn wrap<const SSL: bool>(
+        self,
+        handler: impl Fn(HttpResponseStruct<SSL>, HttpRequest) + Unpin + 'static,
+    ) -> impl Fn(HttpResponseStruct<SSL>, HttpRequest) + Unpin + 'static {
+        move |res, req| {
+            self.apply(&res);
+            handler(res, req);
+        }
+    }
+}
+
+impl<const SSL: bool> Application<SSL> {
+    /// Times every request and WebSocket upgrade, see `RequestTimeouts`.
+    pub fn request_timeouts(&mut self, timeouts: RequestTimeouts) -> &mut Self {
+        if let Some(timeout) = timeouts.headers {
+            self.time_headers(timeout);
+        }
+        self.before_request(move |res, _| {
+            timeouts.apply(res);
+            ControlFlow::Continue(())
+        })
+    }
+
+    fn time_headers(&mut self, timeout: Duration) {
+        let app = self.native.app_ptr;
+        let installed = HEADER_TIMEOUTS
+            .with(|timeouts| timeouts.borrow_mut().insert(app as usize, timeout))
+            .is_some();
+        if installed {
+            return;
+        }
+        unsafe {
+            uws_filter(
+                SSL as c_int,
+                app,
+                Some(header_filter::<SSL>),
+                app as *mut c_void,
+            )
+        };
+        // First, so hooks that keep the request waiting can't get it closed
+        self.before_request_first(|res, _| {
+            headers_received(res.native);
+            ControlFlow::Continue(())
+        });
+    }
+}
+
+#[derive(Clone, Copy)]
+enum TimeoutKind {
+    Body,
+    Response,
+}
+
+struct PendingRequest {
+    body_timeout: Option<Duration>,
+    body_timer: Option<Timer>,
+    response_timer: Option<Timer>,
+    // Whether anything of the response was written
+    started: bool,
+    // The callbacks registered while timed, uWS calls ours instead
+    on_aborted: Option<(AbortCallback, *mut c_void)>,
+    on_data: Option<(DataCallback, *mut c_void)>,
+}
+
+impl PendingRequest {
+    fn cancel_timers(&self) {
+        for timer in [&self.body_timer, &self.response_timer]
+            .into_iter()
+            .flatten()
+        {
+            timer.cancel();
+        }
+    }
+}
+
+thread_local! {
+    // Keyed by the native response. Entries are removed once the response completes or is
+    // aborted, so timers never fire for a socket that is gone.
+    static PENDING: RefCell<HashMap<usize, PendingRequest>> = RefCell::new(HashMap::new());
+}
+
+fn take_pending(native: *mut uws_res_t) -> Option<PendingRequest> {
+    PENDING.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        if pending.is_empty() {
+            return None;
+        }
+        pending.remove(&(native as usize))
+    })
+}
+
+fn with_pending<R>(native: *mut uws_res_t, f: impl FnOnce(&mut PendingRequest) -> R) -> Option<R> {
+    PENDING.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        if pending.is_empty() {
+            return None;
+        }
+        pending.get_mut(&(native as usize)).map(f)
+    })
+}
+
+/// Stops the timeouts once the response completed. A body still being read goes straight to
+/// its handler again.
+pub(crate) fn disarm<const SSL: bool>(native: *mut uws_res_t) {
+    let Some(pending) = take_pending(native) else {
+        return;
+    };
+    pending.cancel_timers();
+    if let Some((callback, user_data)) = pending.on_data {
+        unsafe { uws_res_on_data(SSL as c_int, native, Some(callback), user_data) };
+    }
+}
+
+pub(crate) fn mark_started(native: *mut uws_res_t) {
+    with_pending(native, |pending| pending.started = true);
+}
+
+/// Registers an abort callback, through the timeouts if the request is timed.
+pub(crate) unsafe fn set_on_aborted<const SSL: bool>(
+    native: *mut uws_res_t,
+    callback: AbortCallback,
+    user_data: *mut c_void,
+) {
+    let timed = with_pending(native, |pending| {
+        pending.on_aborted = Some((callback, user_data))
+    });
+    match timed {
+        Some(()) => uws_res_on_aborted(SSL as c_int, native, Some(timed_on_aborted), null_mut()),
+        None => uws_res_on_aborted(SSL as c_int, native, Some(callback), user_data),
+    }
+}
+
+/// Registers a body callback, through the timeouts if the request is timed.
+pub(crate) unsafe fn set_on_data<const SSL: bool>(
+    native: *mut uws_res_t,
+    callback: DataCallback,
+    user_data: *mut c_void,
+) {
+    let timed = with_pending(native, |pending| {
+        pending.on_data = Some((callback, user_data))
+    });
+    match timed {
+        Some(()) => uws_res_on_data(SSL as c_int, native, Some(timed_on_data::<SSL>), null_mut()),
+        None => uws_res_on_data(SSL as c_int, native, Some(callback), user_data),
+    }
+}
+
+fn expire<const SSL: bool>(key: usize, kind: TimeoutKind) {
+    let native = key as *mut uws_res_t;
+    // Nobody reads the body, only the response timeout is left
+    if let TimeoutKind::Body = kind {
+        if with_pending(native, |pending| pending.on_data.is_none()) == Some(true) {
+            return;
+        }
+    }
+    let Some(pending) = take_pending(native) else {
+        return;
+    };
+    pending.cancel_timers();
+
+    // Without an entry our callbacks drop whatever uWS still delivers
+    if let Some((callback, user_data)) = pending.on_aborted {
+        unsafe { callback(native, user_data) };
+    }
+
+    let res = HttpResponseStruct::<SSL>::new(native);
+    if pending.started {
+        res.close();
+        return;
+    }
+    res.write_status_code(match kind {
+        TimeoutKind::Body => StatusCode::REQUEST_TIMEOUT,
+        TimeoutKind::Response => StatusCode::SERVICE_UNAVAILABLE,
+    });
+    res.end(None, true);
+}
+
+unsafe extern "C" fn timed_on_aborted(res: *mut uws_res_t, _: *mut c_void) {
+    let Some(pending) = take_pending(res) else {
+        return;
+    };
+    pending.cancel_timers();
+    if let Some((callback, user_data)) = pending.on_aborted {
+        callback(res, user_data);
+    }
+}
+
+unsafe extern "C" fn timed_on_data<const SSL: bool>(
+    res: *mut uws_res_t,
+    chunk: *const c_char,
+    chunk_length: usize,
+    is_end: bool,
+    _: *mut c_void,
+) {
+    let forward = with_pending(res, |pending| {
+        let on_data = pending.on_data?;
+        if let Some(timer) = pending.body_timer.take() {
+            timer.cancel();
+        }
+        if is_end {
+            pending.on_data = None;
+        } else if let Some(timeout) = pending.body_timeout {
+            let key = res as usize;
+            pending.body_timer = Some(set_timeout(get_loop(), timeout, move || {
+                expire::<SSL>(key, TimeoutKind::Body)
+            }));
+        }
+        Some(on_data)
+    })
+    .flatten();
+
+    if let Some((callback, user_data)) = forward {
+        callback(res, chunk, chunk_length, is_end, user_data);
+    }
+}
+
+struct HeaderWait {
+    timeout: Duration,
+    // Running while the connection waits for the headers of a request
+    timer: Option<Timer>,
+}
+
+thread_local! {
+    // Keyed by the native app, for apps with a header timeout
+    static HEADER_TIMEOUTS: RefCell<HashMap<usize, Duration>> = RefCell::new(HashMap::new());
+    // Keyed by the native socket, from the moment it is accepted until it closes or upgrades
+    static CONNECTIONS: RefCell<HashMap<usize, HeaderWait>> = RefCell::new(HashMap::new());
+}
+
+// Its filter stays installed but finds nothing to do anymore
+pub(crate) fn forget_header_timeout(app: *mut uws_app_t) {
+    HEADER_TIMEOUTS.with(|timeouts| timeouts.borrow_mut().remove(&(app as usize)));
+}
+
+unsafe extern "C" fn header_filter<const SSL: bool>(
+    res: *mut uws_res_t,
+    event: c_int,
+    user_data: *mut c_void,
+) {
+    if event < 0 {
+        stop_header_timeout(res);
+        return;
+    }
+
+    // Earlier filters may have turned the connection away already
+    if us_socket_is_closed(SSL as c_int, res as *mut us_socket_t) != 0 {
+        return;
+    }
+    let timeout =
+        HEADER_TIMEOUTS.with(|timeouts| timeouts.borrow().get(&(user_data as usize)).copied());
+    if let Some(timeout) = timeout {
+        CONNECTIONS.with(|connections| {
+            connections.borrow_mut().insert(
+                res as usize,
+                HeaderWait {
+                    timeout,
+                    timer: None,
+                },
+            )
+        });
+        await_headers::<SSL>(res);
+    }
+}
+
+/// Starts the header timeout over once the connection is done with a response.
+pub(crate) fn await_headers<const SSL: bool>(native: *mut uws_res_t) {
+    let key = native as usize;
+    CONNECTIONS.with(|connections| {
+        let mut connections = connections.borrow_mut();
+        let Some(wait) = connections.get_mut(&key) else {
+            return;
+        };
+        if let Some(timer) = wait.timer.take() {
+            timer.cancel();
+        }
+        wait.timer = Some(set_timeout(get_loop(), wait.timeout, move || {
+            headers_expired::<SSL>(key)
+        }));
+    });
+}
+
+fn headers_received(native: *mut uws_res_t) {
+    CONNECTIONS.with(|connections| {
+        let mut connections = connections.borrow_mut();
+        if let Some(timer) = connections
+            .get_mut(&(native as usize))
+            .and_then(|wait| wait.timer.take())
+        {
+            timer.cancel();
+        }
+    });
+}
+
+/// Drops the header timeout of a connection that closed or left HTTP for WebSocket.
+pub(crate) fn stop_header_timeout(native: *mut uws_res_t) {
+    let wait = CONNECTIONS.with(|connections| {
+        let mut connections = connections.borrow_mut();
+        if connections.is_empty() {
+            return None;
+        }
+        connections.remove(&(native as usize))
+    });
+    if let Some(timer) = wait.and_then(|wait| wait.timer) {
+        timer.cancel();
+    }
+}
+
+fn headers_expired<const SSL: bool>(key: usize) {
+    let removed = CONNECTIONS.with(|connections| connections.borrow_mut().remove(&key));
+    if removed.is_some() {
+        unsafe { us_socket_close(SSL as c_int, key as *mut us_socket_t, 0, null_mut()) };
+    }
+}
@@ -0,0 +1,244 @@
+use std::cell::{Cell, RefCell};
+use std::fmt::Write as _;
+use std::io::Write;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+use crate::chunked_writer::{ChunkedWriter, DEFAULT_MAX_BUFFERED};
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponseStruct;
+use crate::status_code::StatusCode;
+use crate::uws_loop::{get_loop, set_interval, Timer};
+
+/// A single Server-Sent Event.
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+    data: String,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Self {
+        Event {
+            data: data.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Tells the browser how long to wait before reconnecting.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    fn format(&self) -> String {
+        let mut out = String::with_capacity(self.data.len() + 32);
+        // Line breaks would start a new field, so they are dropped from single line fields
+        if let Some(event) = &self.event {
+            let _ = writeln!(out, "event: {}", single_line(event));
+        }
+        if let Some(id) = &self.id {
+            let _ = writeln!(out, "id: {}", single_line(id));
+        }
+        if let Some(retry) = self.retry {
+            let _ = writeln!(out, "retry: {}", retry.as_millis());
+        }
+        for line in self
+            .data
+            .split("\r\n")
+            .flat_map(|line| line.split(['\r', '\n']))
+        {
+            let _ = writeln!(out, "data: {line}");
+        }
+        out.push('\n');
+        out
+    }
+}
+
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], "")
+}
+
+type OnCloseHandler = Box<dyn FnOnce()>;
+
+struct EventStreamState<const SSL: bool> {
+    res: HttpResponseStruct<SSL>,
+    writer: RefCell<Option<ChunkedWriter<SSL>>>,
+    max_buffered: Cell<usize>,
+    keep_alive: RefCell<Option<Timer>>,
+    on_close: RefCell<Option<OnCloseHandler>>,
+    last_event_id: Option<String>,
+}
+
+/// An open `text/event-stream` response.
+///
+/// Clones share the same stream. The response is ended by `close` or once the last clone is
+/// dropped, an aborted client cancels the keep-alive and runs the `on_close` handler.
+///
+/// Events for a client that doesn't read are kept in memory up to `max_buffered` bytes. An
+/// event that doesn't fit closes the connection, the browser reconnects with the
+/// `Last-Event-ID` of the last event it got.
+#[derive(Clone)]
+pub struct EventStream<const SSL: bool> {
+    state: Rc<EventStreamState<SSL>>,
+}
+
+impl<const SSL: bool> EventStream<SSL> {
+    pub fn new(res: &HttpResponseStruct<SSL>, req: &HttpRequest) -> Self {
+        let last_event_id = req
+            .get_header("last-event-id")
+            .filter(|id| !id.is_empty())
+            .map(str::to_string);
+
+        res.write_status_code(StatusCode::OK);
+        res.write_header("Content-Type", "text/event-stream");
+        res.write_header("Cache-Control", "no-cache");
+        // Keeps nginx style proxies from buffering the stream
+        res.write_header("X-Accel-Buffering", "no");
+
+        let state = Rc::new(EventStreamState {
+            res: HttpResponseStruct::new(res.native),
+            writer: RefCell::new(None),
+            max_buffered: Cell::new(DEFAULT_MAX_BUFFERED),
+            keep_alive: RefCell::new(None),
+            on_close: RefCell::new(None),
+            last_event_id,
+        });
+
+        let mut writer = ChunkedWriter::new(res);
+        let weak_state = Rc::downgrade(&state);
+        writer.on_aborted(move || {
+            if let Some(state) = weak_state.upgrade() {
+                state.shutdown();
+            }
+        });
+        // Sends the headers right away, browsers only fire `open` once they got them
+        let _ = writer.write_all(b":\n\n");
+        *state.writer.borrow_mut() = Some(writer);
+
+        EventStream { state }
+    }
+
+    /// The `Last-Event-ID` the browser sent when reconnecting.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.state.last_event_id.as_deref()
+    }
+
+    /// Sends a comment every `interval` so proxies don't drop an idle connection. None is sent
+    /// while earlier data is still waiting for the client.
+    pub fn keep_alive(&self, interval: Duration) {
+        let weak_state: Weak<EventStreamState<SSL>> = Rc::downgrade(&self.state);
+        let timer = set_interval(get_loop(), interval, move || {
+            let Some(state) = weak_state.upgrade() else {
+                return;
+            };
+            let pending = state
+                .writer
+                .borrow()
+                .as_ref()
+                .is_some_and(|writer| writer.is_backpressured());
+            if !pending {
+                state.write(b": keep-alive\n\n");
+            }
+        });
+
+        if let Some(previous) = self.state.keep_alive.replace(Some(timer)) {
+            previous.cancel();
+        }
+    }
+
+    /// 1 MiB by default.
+    pub fn max_buffered(&self, bytes: usize) {
+        self.state.max_buffered.set(bytes);
+        if let Some(writer) = self.state.writer.borrow_mut().as_mut() {
+            writer.max_buffered(bytes);
+        }
+    }
+
+    /// Returns false once the stream is closed.
+    pub fn send(&self, event: &Event) -> bool {
+        self.state.write(event.format().as_bytes())
+    }
+
+    pub fn send_data(&self, data: &str) -> bool {
+        self.send(&Event::new(data))
+    }
+
+    pub fn comment(&self, comment: &str) -> bool {
+        self.state
+            .write(format!(": {}\n\n", single_line(comment)).as_bytes())
+    }
+
+    /// Called once when the stream ends, either by `close` or because the client went away.
+    pub fn on_close(&self, handler: impl FnOnce() + 'static) {
+        *self.state.on_close.borrow_mut() = Some(Box::new(handler));
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.writer.borrow().is_none()
+    }
+
+    pub fn close(&self) {
+        self.state.shutdown();
+    }
+}
+
+impl<const SSL: bool> EventStreamState<SSL> {
+    fn write(&self, data: &[u8]) -> bool {
+        let mut writer = self.writer.borrow_mut();
+        let Some(open_writer) = writer.as_mut() else {
+            return false;
+        };
+        // Half an event would garble the stream, so events go out whole or not at all
+        if !open_writer.is_backpressured()
+            || open_writer.buffered_len() + data.len() <= self.max_buffered.get()
+        {
+            return open_writer.write_all(data).is_ok();
+        }
+        drop(writer);
+
+        // Closing runs the abort handler, which shuts the stream down
+        self.res.close();
+        self.shutdown();
+        false
+    }
+
+    fn shutdown(&self) {
+        if let Some(timer) = self.keep_alive.take() {
+            timer.cancel();
+        }
+        // Dropping the writer ends the response, unless it was aborted already
+        let writer = self.writer.take();
+        drop(writer);
+
+        let handler = self.on_close.take();
+        if let Some(handler) = handler {
+            handler();
+        }
+    }
+}
+
+impl<const SSL: bool> Drop for EventStreamState<SSL> {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl<const SSL: bool> HttpResponseStruct<SSL> {
+    /// Turns this response into a Server-Sent Events stream, see `EventStream`.
+    pub fn event_stream(&self, req: &HttpRequest) -> EventStream<SSL> {
+        EventStream::new(self, req)
+    }
+}
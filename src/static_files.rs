@@ -0,0 +1,301 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponseStruct;
+use crate::status_code::StatusCode;
+use crate::utils::{format_http_date, parse_http_date, percent_decode};
+
+pub(crate) struct StaticDir {
+    prefix: String,
+    root: PathBuf,
+}
+
+enum ResolveError {
+    Forbidden,
+    NotFound,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum ByteRange {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+impl StaticDir {
+    pub(crate) fn new(prefix: &str, root: PathBuf) -> io::Result<Self> {
+        Ok(StaticDir {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            root: root.canonicalize()?,
+        })
+    }
+
+    pub(crate) fn pattern(&self) -> String {
+        format!("{}/*", self.prefix)
+    }
+
+    pub(crate) fn serve<const SSL: bool>(&self, res: HttpResponseStruct<SSL>, req: HttpRequest) {
+        let url = req.get_url();
+        let relative = url.strip_prefix(self.prefix.as_str()).unwrap_or(url);
+
+        let path = match self.resolve(relative) {
+            Ok(path) => path,
+            Err(ResolveError::Forbidden) => return respond_empty(&res, StatusCode::FORBIDDEN),
+            Err(ResolveError::NotFound) => return respond_empty(&res, StatusCode::NOT_FOUND),
+        };
+
+        let (mut file, metadata) = match File::open(&path).and_then(|file| {
+            let metadata = file.metadata()?;
+            Ok((file, metadata))
+        }) {
+            Ok(opened) => opened,
+            Err(_) => return respond_empty(&res, StatusCode::NOT_FOUND),
+        };
+
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+        let modified_secs = modified
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let etag = format!("\"{modified_secs:x}-{len:x}\"");
+        let last_modified = modified.map(format_http_date);
+
+        if is_not_modified(&req, &etag, modified_secs) {
+            res.write_status_code(StatusCode::NOT_MODIFIED);
+            res.write_header("ETag", &etag);
+            if let Some(last_modified) = &last_modified {
+                res.write_header("Last-Modified", last_modified);
+            }
+            res.end_without_body(false);
+            return;
+        }
+
+        let (start, end) = match parse_range(req.get_header("range"), len) {
+            ByteRange::Full => {
+                res.write_status_code(StatusCode::OK);
+                (0, len)
+            }
+            ByteRange::Partial(start, end) => {
+                res.write_status_code(StatusCode::PARTIAL_CONTENT);
+                res.write_header("Content-Range", &format!("bytes {start}-{end}/{len}"));
+                (start, end + 1)
+            }
+            ByteRange::Unsatisfiable => {
+                res.write_status_code(StatusCode::RANGE_NOT_SATISFIABLE);
+                res.write_header("Content-Range", &format!("bytes */{len}"));
+                res.end(None, false);
+                return;
+            }
+        };
+
+        res.write_header("Content-Type", content_type(&path));
+        res.write_header("ETag", &etag);
+        res.write_header("Accept-Ranges", "bytes");
+        if let Some(last_modified) = &last_modified {
+            res.write_header("Last-Modified", last_modified);
+        }
+
+        if start > 0 && file.seek(SeekFrom::Start(start)).is_err() {
+            res.close();
+            return;
+        }
+        res.stream(end - start, file.take(end - start));
+    }
+
+    fn resolve(&self, relative: &str) -> Result<PathBuf, ResolveError> {
+        let decoded = percent_decode(relative, false);
+        let mut path = self.root.clone();
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return Err(ResolveError::Forbidden),
+                segment if segment.contains(['\\', '\0']) => return Err(ResolveError::Forbidden),
+                segment => path.push(segment),
+            }
+        }
+
+        let mut path = self.within_root(&path)?;
+        if path.is_dir() {
+            path = self.within_root(&path.join("index.html"))?;
+        }
+        if !path.is_file() {
+            return Err(ResolveError::NotFound);
+        }
+        Ok(path)
+    }
+
+    // Canonicalizing also resolves symlinks, which must not lead outside the root either
+    fn within_root(&self, path: &Path) -> Result<PathBuf, ResolveError> {
+        let path = path.canonicalize().map_err(|_| ResolveError::NotFound)?;
+        if !path.starts_with(&self.root) {
+            return Err(ResolveError::Forbidden);
+        }
+        Ok(path)
+    }
+}
+
+fn respond_empty<const SSL: bool>(res: &HttpResponseStruct<SSL>, status: StatusCode) {
+    res.write_status_code(status);
+    res.end(None, false);
+}
+
+fn is_not_modified(req: &HttpRequest, etag: &str, modified_secs: u64) -> bool {
+    if let Some(if_none_match) = req.get_header("if-none-match") {
+        return if_none_match.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        });
+    }
+
+    req.get_header("if-modified-since")
+        .and_then(parse_http_date)
+        .and_then(|since| since.duration_since(UNIX_EPOCH).ok())
+        .map(|since| modified_secs <= since.as_secs())
+        .unwrap_or(false)
+}
+
+// Only single ranges are honoured, anything else falls back to the full body as RFC 9110 allows
+fn parse_range(header: Option<&str>, len: u64) -> ByteRange {
+    let spec = match header.and_then(|header| header.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Full,
+    };
+
+    if start.is_empty() {
+        return match end.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial(len.saturating_sub(suffix), len - 1),
+            Err(_) => ByteRange::Full,
+        };
+    }
+
+    let start = match start.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return ByteRange::Full,
+    };
+    let end = if end.is_empty() {
+        len.saturating_sub(1)
+    } else {
+        match end.parse::<u64>() {
+            Ok(end) if end >= start => end.min(len.saturating_sub(1)),
+            _ => return ByteRange::Full,
+        }
+    };
+
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") => "audio/ogg",
+        Some("wav") => "audio/wav",
+        Some("webmanifest") => "application/manifest+json",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ByteRange::*;
+    use super::*;
+
+    fn range(header: &str, len: u64) -> ByteRange {
+        parse_range(Some(header), len)
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(range("bytes=0-99", 1000), Partial(0, 99));
+        assert_eq!(range(" bytes=900- ", 1000), Partial(900, 999));
+        assert_eq!(range("bytes=-100", 1000), Partial(900, 999));
+        // Ranges past the end are cut to the length
+        assert_eq!(range("bytes=500-5000", 1000), Partial(500, 999));
+        assert_eq!(range("bytes=-5000", 1000), Partial(0, 999));
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(range("bytes=1000-", 1000), Unsatisfiable);
+        assert_eq!(range("bytes=-0", 1000), Unsatisfiable);
+        assert_eq!(range("bytes=-10", 0), Unsatisfiable);
+        assert_eq!(range("bytes=0-", 0), Unsatisfiable);
+    }
+
+    #[test]
+    fn serves_everything_for_other_ranges() {
+        assert_eq!(parse_range(None, 1000), Full);
+        for header in [
+            "bytes=0-10,20-30",
+            "items=0-10",
+            "bytes=10-5",
+            "bytes=a-b",
+            "bytes=10",
+            "bytes=-",
+        ] {
+            assert_eq!(range(header, 1000), Full, "{header}");
+        }
+    }
+
+    #[test]
+    fn keeps_symlinked_indexes_inside_the_root() {
+        let base = std::env::temp_dir().join(format!("static-files-{}", std::process::id()));
+        let root = base.join("root");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::create_dir_all(root.join("leak")).unwrap();
+        std::fs::write(root.join("docs/index.html"), "docs").unwrap();
+        std::fs::write(base.join("secret"), "secret").unwrap();
+        std::os::unix::fs::symlink(base.join("secret"), root.join("leak/index.html")).unwrap();
+
+        let dir = StaticDir::new("/files", root).unwrap();
+        let served = dir.resolve("/docs/").ok();
+        let leaked = dir.resolve("/leak").err();
+        let missing_root = StaticDir::new("/files", base.join("missing")).is_err();
+        std::fs::remove_dir_all(&base).unwrap();
+
+        assert_eq!(served, Some(dir.root.join("docs/index.html")));
+        assert!(matches!(leaked, Some(ResolveError::Forbidden)));
+        assert!(missing_root);
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::app::{app_publish, AppLiveness, Application, NativeApp};
+use crate::uws_loop::{get_loop, loop_defer, UwsLoop};
+use crate::websocket::Opcode;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct BusMemberId(usize);
+
+#[derive(Clone)]
+struct BusMember {
+    id: BusMemberId,
+    native: NativeApp,
+    uws_loop: UwsLoop,
+    liveness: AppLiveness,
+    ssl: bool,
+}
+
+/// Mirrors publishes across apps that run on different threads.
+///
+/// Every delivery is handed to the member's loop with `loop_defer`, which runs callbacks in
+/// the order they were deferred, so messages from a single publisher keep their order.
+/// Members whose app was closed or dropped are left out and unregistered.
+#[derive(Clone, Default)]
+pub struct TopicBus {
+    members: Arc<RwLock<Vec<BusMember>>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+impl TopicBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Must be called on the thread that created (and will run) the app.
+    pub fn register<const SSL: bool>(&self, app: &Application<SSL>) -> BusMemberId {
+        let id = BusMemberId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let member = BusMember {
+            id,
+            native: app.native,
+            uws_loop: get_loop(),
+            liveness: app.liveness.clone(),
+            ssl: SSL,
+        };
+        self.members
+            .write()
+            .expect("TopicBus lock is poisoned")
+            .push(member);
+        id
+    }
+
+    pub fn unregister(&self, id: BusMemberId) {
+        self.members
+            .write()
+            .expect("TopicBus lock is poisoned")
+            .retain(|member| member.id != id);
+    }
+
+    pub fn publish(&self, topic: &str, message: &[u8], opcode: Opcode, compress: bool) {
+        self.fan_out(None, topic, message, opcode, compress);
+    }
+
+    /// Publishes to every member except `origin`, for messages that were already published
+    /// locally, e.g. with `WebSocketStruct::publish`.
+    pub fn publish_from(
+        &self,
+        origin: BusMemberId,
+        topic: &str,
+        message: &[u8],
+        opcode: Opcode,
+        compress: bool,
+    ) {
+        self.fan_out(Some(origin), topic, message, opcode, compress);
+    }
+
+    fn fan_out(
+        &self,
+        origin: Option<BusMemberId>,
+        topic: &str,
+        message: &[u8],
+        opcode: Opcode,
+        compress: bool,
+    ) {
+        let topic: Arc<str> = Arc::from(topic);
+        let message: Arc<[u8]> = Arc::from(message);
+
+        let mut ended = false;
+        let members = self.members.read().expect("TopicBus lock is poisoned");
+        for member in members.iter().filter(|member| Some(member.id) != origin) {
+            // The loop of an app that ended may be gone already
+            ended |= !member.liveness.while_alive(|| {
+                let member = member.clone();
+                let topic = topic.clone();
+                let message = message.clone();
+
+                loop_defer(member.uws_loop, move || {
+                    // Closed while the message was on its way
+                    if !member.liveness.is_alive() {
+                        return;
+                    }
+                    unsafe {
+                        app_publish(
+                            member.ssl,
+                            member.native,
+                            &topic,
+                            &message,
+                            opcode,
+                            compress,
+                        )
+                    };
+                });
+            });
+        }
+        drop(members);
+
+        if ended {
+            self.members
+                .write()
+                .expect("TopicBus lock is poisoned")
+                .retain(|member| member.liveness.is_alive());
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use std::cell::{Cell, RefCell};
+use std::ffi::{c_char, c_int, c_void};
+use std::future::poll_fn;
+use std::ptr::{null, null_mut};
+use std::rc::Rc;
+
+use http::{Request, Response};
+use libuwebsockets_sys::{uws_res_cork, uws_res_t};
+use tower_service::Service;
+
+use crate::app::Application;
+use crate::http_response::HttpResponseStruct;
+use crate::request_timeout::{set_on_aborted, set_on_data};
+use crate::status_code::StatusCode;
+use crate::utils::read_buf_from_ptr;
+use crate::uws_loop::spawn_local;
+
+impl<const SSL: bool> Application<SSL> {
+    /// Answers every request matching `pattern` with a tower `Service`.
+    ///
+    /// The body is buffered up to `max_body_size` bytes, larger bodies get a 413 without
+    /// calling the service. The service future runs on the loop, see `spawn_local`, and its
+    /// response is dropped if the client went away in the meantime. Errors of the service
+    /// are answered with a 500.
+    pub fn service<S, B>(&mut self, pattern: &str, max_body_size: usize, service: S) -> &mut Self
+    where
+        S: Service<Request<Vec<u8>>, Response = Response<B>> + Clone + 'static,
+        S::Future: 'static,
+        B: AsRef<[u8]> + 'static,
+    {
+        let service = Rc::new(service);
+        self.any(pattern, move |res, req| {
+            let head = match req.to_http_request() {
+                Ok(head) => head,
+                Err(_) => {
+                    res.write_status_code(StatusCode::BAD_REQUEST);
+                    res.end(None, true);
+                    return;
+                }
+            };
+            ServiceCall::<S, SSL>::start(&res, head, max_body_size, S::clone(&service));
+        })
+    }
+}
+
+struct ServiceCall<S, const SSL: bool> {
+    res: HttpResponseStruct<SSL>,
+    service: RefCell<Option<S>>,
+    head: RefCell<Option<Request<()>>>,
+    body: RefCell<Vec<u8>>,
+    max_body_size: usize,
+    aborted: Cell<bool>,
+    responded: Cell<bool>,
+    // The references owned by the uWS callbacks, null once they were given back.
+    // onData outlives the response, uWS keeps delivering the body until its last chunk.
+    data_registration: Cell<*const ServiceCall<S, SSL>>,
+    abort_registration: Cell<*const ServiceCall<S, SSL>>,
+}
+
+impl<S, B, const SSL: bool> ServiceCall<S, SSL>
+where
+    S: Service<Request<Vec<u8>>, Response = Response<B>> + 'static,
+    S::Future: 'static,
+    B: AsRef<[u8]> + 'static,
+{
+    fn start(res: &HttpResponseStruct<SSL>, head: Request<()>, max_body_size: usize, service: S) {
+        let native = res.native;
+        let call = Rc::new(ServiceCall {
+            res: HttpResponseStruct::<SSL>::new(native),
+            service: RefCell::new(Some(service)),
+            head: RefCell::new(Some(head)),
+            body: RefCell::new(Vec::new()),
+            max_body_size,
+            aborted: Cell::new(false),
+            responded: Cell::new(false),
+            data_registration: Cell::new(null()),
+            abort_registration: Cell::new(null()),
+        });
+
+        let abort_registration = Rc::into_raw(call.clone());
+        call.abort_registration.set(abort_registration);
+        let data_registration = Rc::into_raw(call.clone());
+        call.data_registration.set(data_registration);
+        // uWS terminates the process if the handler returns without responding or an abort handler
+        unsafe {
+            set_on_aborted::<SSL>(
+                native,
+                service_on_aborted::<S, B, SSL>,
+                abort_registration as *mut c_void,
+            );
+            set_on_data::<SSL>(
+                native,
+                service_on_data::<S, B, SSL>,
+                data_registration as *mut c_void,
+            );
+        }
+    }
+
+    fn on_data(self: Rc<Self>, chunk: &[u8], is_end: bool) {
+        if self.responded.get() {
+            return;
+        }
+
+        let mut body = self.body.borrow_mut();
+        if body.len() + chunk.len() > self.max_body_size {
+            drop(body);
+            self.body.take();
+            self.ignore_remaining_data();
+            self.respond(
+                Response::builder()
+                    .status(StatusCode::PAYLOAD_TOO_LARGE.as_u16())
+                    .body(Vec::new())
+                    .expect("status is valid"),
+                true,
+            );
+            return;
+        }
+        body.extend_from_slice(chunk);
+        drop(body);
+
+        if !is_end {
+            return;
+        }
+        self.release(&self.data_registration);
+
+        let (Some(head), Some(service)) = (self.head.take(), self.service.take()) else {
+            return;
+        };
+        let request = Request::from_parts(head.into_parts().0, self.body.take());
+        spawn_local(self.clone().call(service, request));
+    }
+
+    async fn call(self: Rc<Self>, mut service: S, request: Request<Vec<u8>>) {
+        let result = match poll_fn(|cx| service.poll_ready(cx)).await {
+            Ok(()) => service.call(request).await,
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(response) => {
+                let (parts, body) = response.into_parts();
+                self.respond(Response::from_parts(parts, body.as_ref().to_vec()), false);
+            }
+            Err(_) => self.respond(
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR.as_u16())
+                    .body(Vec::new())
+                    .expect("status is valid"),
+                false,
+            ),
+        }
+    }
+
+    fn on_aborted(&self) {
+        self.aborted.set(true);
+        self.service.take();
+        self.body.take();
+        // A closed socket delivers no more data either
+        self.release(&self.abort_registration);
+        self.release(&self.data_registration);
+    }
+
+    fn respond(self: &Rc<Self>, response: Response<Vec<u8>>, close_connection: bool) {
+        if self.aborted.get() || self.responded.replace(true) {
+            return;
+        }
+
+        // The service may finish outside of a uWS callback, cork to send everything at once
+        let mut response = Some(response);
+        let mut send = || {
+            let Some(response) = response.take() else {
+                return;
+            };
+            let res = &self.res;
+            if res.send_http_response(response, close_connection).is_err() {
+                res.write_status_code(StatusCode::INTERNAL_SERVER_ERROR);
+                res.end(None, close_connection);
+            }
+        };
+        let mut send: &mut dyn FnMut() = &mut send;
+        unsafe {
+            uws_res_cork(
+                SSL as c_int,
+                self.res.native,
+                Some(corked_send),
+                &mut send as *mut &mut dyn FnMut() as *mut c_void,
+            );
+        }
+        // end marks the response as done, which unregisters onAborted
+        self.release(&self.abort_registration);
+    }
+
+    // The rest of an oversized body still arrives, without the call state
+    fn ignore_remaining_data(&self) {
+        if self.data_registration.get().is_null() {
+            return;
+        }
+        unsafe {
+            set_on_data::<SSL>(self.res.native, ignore_data, null_mut());
+        }
+        self.release(&self.data_registration);
+    }
+
+    fn release(&self, registration: &Cell<*const Self>) {
+        let registration = registration.replace(null());
+        if !registration.is_null() {
+            unsafe { drop(Rc::from_raw(registration)) };
+        }
+    }
+}
+
+unsafe extern "C" fn service_on_data<S, B, const SSL: bool>(
+    _: *mut uws_res_t,
+    chunk: *const c_char,
+    chunk_length: usize,
+    is_end: bool,
+    user_data: *mut c_void,
+) where
+    S: Service<Request<Vec<u8>>, Response = Response<B>> + 'static,
+    S::Future: 'static,
+    B: AsRef<[u8]> + 'static,
+{
+    let call = user_data as *const ServiceCall<S, SSL>;
+    // Keep the state alive while it may give back its own registration
+    Rc::increment_strong_count(call);
+    let call = Rc::from_raw(call);
+    call.on_data(read_buf_from_ptr(chunk, chunk_length), is_end);
+}
+
+unsafe extern "C" fn service_on_aborted<S, B, const SSL: bool>(
+    _: *mut uws_res_t,
+    user_data: *mut c_void,
+) where
+    S: Service<Request<Vec<u8>>, Response = Response<B>> + 'static,
+    S::Future: 'static,
+    B: AsRef<[u8]> + 'static,
+{
+    let call = user_data as *const ServiceCall<S, SSL>;
+    Rc::increment_strong_count(call);
+    let call = Rc::from_raw(call);
+    call.on_aborted();
+}
+
+// uws_res_cork runs the callback before returning, so it can borrow from the stack
+unsafe extern "C" fn corked_send(_: *mut uws_res_t, user_data: *mut c_void) {
+    let send = &mut *(user_data as *mut &mut dyn FnMut());
+    send();
+}
+
+unsafe extern "C" fn ignore_data(
+    _: *mut uws_res_t,
+    _: *const c_char,
+    _: usize,
+    _: bool,
+    _: *mut c_void,
+) {
+}
@@ -2,6 +2,7 @@ use std::ffi::CString;
 
 use libuwebsockets_sys::us_socket_context_options_t;
 
+#[derive(Clone)]
 pub struct UsSocketContextOptions {
     pub key_file_name: Option<&'static str>,
     pub cert_file_name: Option<&'static str>,
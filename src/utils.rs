@@ -1,6 +1,9 @@
-use std::ffi::{c_char, c_int};
+use std::borrow::Cow;
+use std::ffi::c_char;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::ptr::null;
 use std::slice::from_raw_parts;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub(crate) unsafe fn read_str_from<'a, T>(
     native: *mut T,
@@ -11,15 +14,6 @@ pub(crate) unsafe fn read_str_from<'a, T>(
     read_str_from_ptr(buf, size)
 }
 
-pub(crate) unsafe fn read_str_from_with_ssl<'a, const SSL: bool, T>(
-    native: *mut T,
-    reader: unsafe extern "C" fn(ssl: c_int, *mut T, *mut *const c_char) -> usize,
-) -> &'a str {
-    let mut buf: *const c_char = null();
-    let size = reader(SSL as c_int, native, &mut buf);
-    read_str_from_ptr(buf, size)
-}
-
 pub(crate) unsafe fn read_str_from_ptr<'a>(ptr: *const c_char, length: usize) -> &'a str {
     read_valid_string_from_ptr(ptr, length)
 }
@@ -28,6 +22,16 @@ pub(crate) unsafe fn read_buf_from_ptr<'a>(buf: *const c_char, len: usize) -> &'
     from_raw_parts(buf as *const u8, len)
 }
 
+// uWS hands out the raw 4 or 16 address bytes, IPv4 clients of dual-stack sockets come as
+// IPv4-mapped IPv6 addresses
+pub(crate) fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    if let Ok(octets) = <[u8; 4]>::try_from(bytes) {
+        return Some(IpAddr::V4(Ipv4Addr::from(octets)));
+    }
+    let octets = <[u8; 16]>::try_from(bytes).ok()?;
+    Some(Ipv6Addr::from(octets).to_canonical())
+}
+
 pub unsafe fn read_valid_string_from_ptr<'a>(ptr: *const c_char, len: usize) -> &'a str {
     let bytes = from_raw_parts(ptr as *const u8, len);
     match std::str::from_utf8(bytes) {
@@ -41,3 +45,218 @@ pub unsafe fn read_valid_string_from_ptr<'a>(ptr: *const c_char, len: usize) ->
         }
     }
 }
+
+pub(crate) fn percent_decode(input: &str, plus_as_space: bool) -> Cow<'_, str> {
+    if !(input.contains('%') || plus_as_space && input.contains('+')) {
+        return Cow::Borrowed(input);
+    }
+
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => match (hex_value(bytes.get(i + 1)), hex_value(bytes.get(i + 2))) {
+                (Some(high), Some(low)) => {
+                    decoded.push(high << 4 | low);
+                    i += 3;
+                    continue;
+                }
+                _ => decoded.push(b'%'),
+            },
+            b'+' if plus_as_space => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+// Splits `a=1&b=2` style input, shared by query strings and urlencoded bodies
+pub(crate) fn parse_urlencoded_pairs(input: &str) -> Vec<(String, String)> {
+    input
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                percent_decode(key, true).into_owned(),
+                percent_decode(value, true).into_owned(),
+            )
+        })
+        .collect()
+}
+
+fn hex_value(byte: Option<&u8>) -> Option<u8> {
+    match byte? {
+        byte @ b'0'..=b'9' => Some(byte - b'0'),
+        byte @ b'a'..=b'f' => Some(byte - b'a' + 10),
+        byte @ b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// IMF-fixdate, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+pub(crate) fn format_http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    let time_of_day = secs % 86400;
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        time_of_day / 3600,
+        time_of_day % 3600 / 60,
+        time_of_day % 60
+    )
+}
+
+// Only IMF-fixdate is accepted, the obsolete formats are not worth the trouble
+pub(crate) fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let mut parts = date.trim().split(' ');
+    let _weekday = parts.next()?;
+    let day: u32 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':');
+    let hours: u64 = time.next()?.parse().ok()?;
+    let minutes: u64 = time.next()?.parse().ok()?;
+    let seconds: u64 = time.next()?.parse().ok()?;
+    if parts.next()? != "GMT" || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    // The date comes from the client, anything outside four digit years is made up
+    if !(1970..=9999).contains(&year) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days
+        .checked_mul(86400)?
+        .checked_add(hours * 3600 + minutes * 60 + seconds)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+// Howard Hinnant's days <-> civil date algorithms
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_http_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(format_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(
+            format_http_date(UNIX_EPOCH),
+            "Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(format_http_date(leap_day), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn parses_http_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(
+            parse_http_date(" Thu, 01 Jan 1970 00:00:00 GMT "),
+            Some(UNIX_EPOCH)
+        );
+
+        let now = UNIX_EPOCH
+            + Duration::from_secs(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            );
+        assert_eq!(parse_http_date(&format_http_date(now)), Some(now));
+    }
+
+    #[test]
+    fn rejects_invalid_http_dates() {
+        for date in [
+            "",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 00 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1969 08:49:37 GMT",
+            "Sun, 06 Nov -300000000000 08:49:37 GMT",
+            "Sun, 06 Nov 300000000000 08:49:37 GMT",
+            "Sun, 06 Nov 9223372036854775807 08:49:37 GMT",
+            "Sun, 06 Nov 1994 08:49:37",
+        ] {
+            assert_eq!(parse_http_date(date), None, "{date}");
+        }
+    }
+
+    #[test]
+    fn percent_decodes() {
+        assert!(matches!(
+            percent_decode("plain", true),
+            Cow::Borrowed("plain")
+        ));
+        assert_eq!(percent_decode("a%20b%2Fc", false), "a b/c");
+        assert_eq!(percent_decode("a+b", true), "a b");
+        assert_eq!(percent_decode("a+b", false), "a+b");
+        assert_eq!(percent_decode("%C3%A9", false), "\u{e9}");
+        // Broken escapes stay as they are
+        assert_eq!(percent_decode("100%", false), "100%");
+        assert_eq!(percent_decode("%zz%4", false), "%zz%4");
+        assert_eq!(percent_decode("%+f", true), "% f");
+        assert_eq!(percent_decode("%ff", false), "\u{fffd}");
+    }
+
+    #[test]
+    fn parses_urlencoded_pairs() {
+        assert_eq!(
+            parse_urlencoded_pairs("a=1&&b=x+y&c&d=%26"),
+            vec![
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "x y".to_string()),
+                ("c".to_string(), String::new()),
+                ("d".to_string(), "&".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,129 @@
+#![allow(non_camel_case_types)]
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::ffi::{c_char, c_int, c_longlong, c_void};
+use std::mem::size_of;
+
+use libuwebsockets_sys::{us_loop_ext, us_loop_iteration_number, us_loop_t};
+
+// The vendored uSockets is built with LIBUS_USE_LIBUV, so every us_loop_t wraps a libuv loop.
+// These are the few libuv calls used to hook into that loop directly.
+
+#[repr(C)]
+pub(crate) struct uv_loop_t {
+    _unused: [u8; 0],
+}
+
+#[repr(C)]
+pub(crate) struct uv_handle_t {
+    _unused: [u8; 0],
+}
+
+pub(crate) const UV_CHECK: c_int = 2;
+pub(crate) const UV_POLL: c_int = 8;
+pub(crate) const UV_PREPARE: c_int = 9;
+
+pub(crate) const UV_READABLE: c_int = 1;
+pub(crate) const UV_WRITABLE: c_int = 2;
+pub(crate) const UV_DISCONNECT: c_int = 4;
+
+pub(crate) type uv_handle_cb = Option<unsafe extern "C" fn(*mut uv_handle_t)>;
+pub(crate) type uv_poll_cb = Option<unsafe extern "C" fn(*mut uv_handle_t, c_int, c_int)>;
+
+extern "C" {
+    fn uv_handle_size(handle_type: c_int) -> usize;
+    pub(crate) fn uv_handle_get_data(handle: *const uv_handle_t) -> *mut c_void;
+    pub(crate) fn uv_handle_set_data(handle: *mut uv_handle_t, data: *mut c_void);
+    fn uv_close(handle: *mut uv_handle_t, close_cb: uv_handle_cb);
+    pub(crate) fn uv_unref(handle: *mut uv_handle_t);
+
+    pub(crate) fn uv_prepare_init(uv_loop: *mut uv_loop_t, prepare: *mut uv_handle_t) -> c_int;
+    pub(crate) fn uv_prepare_start(prepare: *mut uv_handle_t, cb: uv_handle_cb) -> c_int;
+    pub(crate) fn uv_prepare_stop(prepare: *mut uv_handle_t) -> c_int;
+
+    pub(crate) fn uv_check_init(uv_loop: *mut uv_loop_t, check: *mut uv_handle_t) -> c_int;
+    pub(crate) fn uv_check_start(check: *mut uv_handle_t, cb: uv_handle_cb) -> c_int;
+    pub(crate) fn uv_check_stop(check: *mut uv_handle_t) -> c_int;
+
+    pub(crate) fn uv_poll_init(uv_loop: *mut uv_loop_t, poll: *mut uv_handle_t, fd: c_int)
+        -> c_int;
+    pub(crate) fn uv_poll_start(poll: *mut uv_handle_t, events: c_int, cb: uv_poll_cb) -> c_int;
+    pub(crate) fn uv_poll_stop(poll: *mut uv_handle_t) -> c_int;
+}
+
+// Mirrors `struct us_internal_loop_data_t` and the libuv `struct us_loop_t` of the uSockets
+// vendored by libuwebsockets-sys 0.0.10, which the manifest pins. uSockets has no accessor
+// for the libuv loop, `uv_loop_of` checks the mirror against the ones it has instead.
+#[repr(C)]
+struct UsInternalLoopData {
+    sweep_timer: *mut c_void,
+    wakeup_async: *mut c_void,
+    last_write_failed: c_int,
+    head: *mut c_void,
+    iterator: *mut c_void,
+    recv_buf: *mut c_char,
+    ssl_data: *mut c_void,
+    pre_cb: *mut c_void,
+    post_cb: *mut c_void,
+    closed_head: *mut c_void,
+    low_prio_head: *mut c_void,
+    low_prio_budget: c_int,
+    iteration_nr: c_longlong,
+}
+
+#[repr(C, align(16))]
+struct UsLoop {
+    data: UsInternalLoopData,
+    uv_loop: *mut uv_loop_t,
+    is_default: c_int,
+    uv_pre: *mut uv_handle_t,
+    uv_check: *mut uv_handle_t,
+}
+
+#[cfg(target_pointer_width = "64")]
+const _: () = assert!(size_of::<UsLoop>() == 144);
+
+pub(crate) unsafe fn uv_loop_of(loop_ptr: *mut us_loop_t) -> *mut uv_loop_t {
+    // The extension starts right after the struct, the size has to match before reading it
+    let size = us_loop_ext(loop_ptr) as usize - loop_ptr as usize;
+    assert!(
+        size == size_of::<UsLoop>(),
+        "us_loop_t of uSockets takes {size} bytes, not the {} of its mirror",
+        size_of::<UsLoop>()
+    );
+    let us_loop = &*(loop_ptr as *const UsLoop);
+    assert_eq!(
+        us_loop.data.iteration_nr,
+        us_loop_iteration_number(loop_ptr),
+        "us_loop_t of uSockets doesn't match its mirror"
+    );
+    us_loop.uv_loop
+}
+
+fn handle_layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 16).expect("Invalid libuv handle size")
+}
+
+pub(crate) unsafe fn alloc_handle(handle_type: c_int) -> *mut uv_handle_t {
+    alloc_zeroed(handle_layout(uv_handle_size(handle_type))) as *mut uv_handle_t
+}
+
+// For handles whose init failed, libuv never took ownership of those
+pub(crate) unsafe fn free_handle_memory(handle: *mut uv_handle_t, handle_type: c_int) {
+    dealloc(
+        handle as *mut u8,
+        handle_layout(uv_handle_size(handle_type)),
+    );
+}
+
+/// Closes the handle and frees its memory once libuv is done with it.
+/// The handle data is reused to carry the allocation size to the close callback.
+pub(crate) unsafe fn close_handle(handle: *mut uv_handle_t, handle_type: c_int) {
+    uv_handle_set_data(handle, uv_handle_size(handle_type) as *mut c_void);
+    uv_close(handle, Some(free_handle));
+}
+
+unsafe extern "C" fn free_handle(handle: *mut uv_handle_t) {
+    let size = uv_handle_get_data(handle) as usize;
+    dealloc(handle as *mut u8, handle_layout(size));
+}
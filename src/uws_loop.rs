@@ -1,6 +1,26 @@
-use std::ffi::c_void;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::{c_int, c_void};
+use std::future::Future;
+use std::io;
+use std::os::fd::RawFd;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Wake, Waker};
+use std::time::Duration;
 
-use libuwebsockets_sys::{us_loop_t, uws_get_loop, uws_loop_defer};
+use libuwebsockets_sys::{
+    us_create_timer, us_loop_t, us_timer_close, us_timer_ext, us_timer_set, us_timer_t,
+    uws_get_loop, uws_loop_defer,
+};
+
+use crate::uv::{
+    alloc_handle, close_handle, free_handle_memory, uv_check_init, uv_check_start, uv_check_stop,
+    uv_handle_get_data, uv_handle_set_data, uv_handle_t, uv_loop_of, uv_poll_init, uv_poll_start,
+    uv_poll_stop, uv_prepare_init, uv_prepare_start, uv_prepare_stop, uv_unref, UV_CHECK,
+    UV_DISCONNECT, UV_POLL, UV_PREPARE, UV_READABLE, UV_WRITABLE,
+};
 
 #[derive(Clone, Copy, Debug)]
 pub struct UwsLoop {
@@ -46,3 +66,483 @@ unsafe extern "C" fn loop_defer_callback(user_data: *mut c_void) {
 struct CallbackWrapper {
     cb: Box<dyn FnOnce()>,
 }
+
+type LocalTask = Pin<Box<dyn Future<Output = ()>>>;
+
+thread_local! {
+    static LOCAL_TASKS: RefCell<HashMap<u64, LocalTask>> = RefCell::new(HashMap::new());
+    static NEXT_TASK_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+// Wakers may be used from any thread, they only carry the task id back to the loop
+struct TaskWaker {
+    uws_loop: UwsLoop,
+    id: u64,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let id = self.id;
+        loop_defer(self.uws_loop, move || poll_task(id));
+    }
+}
+
+/// Runs a future on the loop of the current thread, it is polled once right away.
+///
+/// Waking it from any thread schedules the next poll with `loop_defer`.
+pub fn spawn_local(future: impl Future<Output = ()> + 'static) {
+    let id = NEXT_TASK_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    });
+    LOCAL_TASKS.with(|tasks| tasks.borrow_mut().insert(id, Box::pin(future)));
+    poll_task(id);
+}
+
+fn poll_task(id: u64) {
+    // The task is taken out while polling, so it can spawn others and duplicate wakes are no-ops
+    let Some(mut task) = LOCAL_TASKS.with(|tasks| tasks.borrow_mut().remove(&id)) else {
+        return;
+    };
+
+    let waker = Waker::from(Arc::new(TaskWaker {
+        uws_loop: get_loop(),
+        id,
+    }));
+    let mut context = Context::from_waker(&waker);
+    if task.as_mut().poll(&mut context).is_pending() {
+        LOCAL_TASKS.with(|tasks| tasks.borrow_mut().insert(id, task));
+    }
+}
+
+// Timers don't keep the loop alive, so closing the app still makes `run` return.
+// They must be created and cancelled on the thread running the loop,
+// use `loop_defer` to get there from other threads.
+pub fn set_timeout(uws_loop: UwsLoop, delay: Duration, cb: impl FnOnce() + 'static) -> Timer {
+    // A delay of 0 would stop the native timer instead of firing it
+    let delay = delay.max(Duration::from_millis(1));
+    Timer::start(
+        uws_loop,
+        delay,
+        None,
+        TimerCallback::Once(Some(Box::new(cb))),
+    )
+}
+
+pub fn set_interval(uws_loop: UwsLoop, interval: Duration, cb: impl FnMut() + 'static) -> Timer {
+    let interval = interval.max(Duration::from_millis(1));
+    Timer::start(
+        uws_loop,
+        interval,
+        Some(interval),
+        TimerCallback::Repeat(Box::new(cb)),
+    )
+}
+
+enum TimerCallback {
+    Once(Option<Box<dyn FnOnce()>>),
+    Repeat(Box<dyn FnMut()>),
+}
+
+struct TimerState {
+    timer: *mut us_timer_t,
+    callback: RefCell<TimerCallback>,
+    closed: Cell<bool>,
+}
+
+impl TimerState {
+    fn close(&self) {
+        if self.closed.replace(true) {
+            return;
+        }
+
+        unsafe {
+            // The native timer owns one reference to the state, release it together with the timer
+            let state_ptr = *(us_timer_ext(self.timer) as *const *const TimerState);
+            us_timer_close(self.timer);
+            drop(Rc::from_raw(state_ptr));
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Timer {
+    state: Rc<TimerState>,
+}
+
+impl Timer {
+    fn start(
+        uws_loop: UwsLoop,
+        delay: Duration,
+        repeat: Option<Duration>,
+        callback: TimerCallback,
+    ) -> Self {
+        unsafe {
+            let timer = us_create_timer(
+                uws_loop.loop_ptr,
+                1,
+                std::mem::size_of::<*const TimerState>() as u32,
+            );
+
+            let state = Rc::new(TimerState {
+                timer,
+                callback: RefCell::new(callback),
+                closed: Cell::new(false),
+            });
+            let ext = us_timer_ext(timer) as *mut *const TimerState;
+            *ext = Rc::into_raw(state.clone());
+
+            us_timer_set(
+                timer,
+                Some(timer_callback),
+                duration_to_ms(delay),
+                repeat.map(duration_to_ms).unwrap_or(0),
+            );
+
+            Timer { state }
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.state.close();
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.state.closed.get()
+    }
+}
+
+fn duration_to_ms(duration: Duration) -> c_int {
+    duration.as_millis().min(c_int::MAX as u128) as c_int
+}
+
+unsafe extern "C" fn timer_callback(timer: *mut us_timer_t) {
+    let state_ptr = *(us_timer_ext(timer) as *const *const TimerState);
+    // Hold our own reference, the callback may cancel the timer and release the native one
+    Rc::increment_strong_count(state_ptr);
+    let state = Rc::from_raw(state_ptr);
+
+    if state.closed.get() {
+        return;
+    }
+
+    let mut callback = state.callback.borrow_mut();
+    match &mut *callback {
+        TimerCallback::Once(cb) => {
+            let cb = cb.take();
+            drop(callback);
+            state.close();
+            if let Some(cb) = cb {
+                cb();
+            }
+        }
+        TimerCallback::Repeat(cb) => cb(),
+    }
+}
+
+// Pre handlers run right before the loop blocks waiting for I/O, post handlers right after it wakes up.
+// Like timers, hooks don't keep the loop alive and must be managed on the loop thread.
+pub fn add_pre_handler(uws_loop: UwsLoop, cb: impl FnMut() + 'static) -> LoopHook {
+    LoopHook::start(uws_loop, LoopHookKind::Pre, Box::new(cb))
+}
+
+pub fn add_post_handler(uws_loop: UwsLoop, cb: impl FnMut() + 'static) -> LoopHook {
+    LoopHook::start(uws_loop, LoopHookKind::Post, Box::new(cb))
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum LoopHookKind {
+    Pre,
+    Post,
+}
+
+struct LoopHookState {
+    handle: *mut uv_handle_t,
+    kind: LoopHookKind,
+    callback: RefCell<Box<dyn FnMut()>>,
+    removed: Cell<bool>,
+}
+
+impl LoopHookState {
+    fn remove(&self) {
+        if self.removed.replace(true) {
+            return;
+        }
+
+        unsafe {
+            let state_ptr = uv_handle_get_data(self.handle) as *const LoopHookState;
+            match self.kind {
+                LoopHookKind::Pre => {
+                    uv_prepare_stop(self.handle);
+                    close_handle(self.handle, UV_PREPARE);
+                }
+                LoopHookKind::Post => {
+                    uv_check_stop(self.handle);
+                    close_handle(self.handle, UV_CHECK);
+                }
+            }
+            drop(Rc::from_raw(state_ptr));
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LoopHook {
+    state: Rc<LoopHookState>,
+}
+
+impl LoopHook {
+    fn start(uws_loop: UwsLoop, kind: LoopHookKind, callback: Box<dyn FnMut()>) -> Self {
+        unsafe {
+            let uv_loop = uv_loop_of(uws_loop.loop_ptr);
+            let handle = match kind {
+                LoopHookKind::Pre => {
+                    let handle = alloc_handle(UV_PREPARE);
+                    uv_prepare_init(uv_loop, handle);
+                    handle
+                }
+                LoopHookKind::Post => {
+                    let handle = alloc_handle(UV_CHECK);
+                    uv_check_init(uv_loop, handle);
+                    handle
+                }
+            };
+
+            let state = Rc::new(LoopHookState {
+                handle,
+                kind,
+                callback: RefCell::new(callback),
+                removed: Cell::new(false),
+            });
+            uv_handle_set_data(handle, Rc::into_raw(state.clone()) as *mut c_void);
+
+            match kind {
+                LoopHookKind::Pre => uv_prepare_start(handle, Some(loop_hook_callback)),
+                LoopHookKind::Post => uv_check_start(handle, Some(loop_hook_callback)),
+            };
+            uv_unref(handle);
+
+            LoopHook { state }
+        }
+    }
+
+    pub fn remove(&self) {
+        self.state.remove();
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.state.removed.get()
+    }
+}
+
+unsafe extern "C" fn loop_hook_callback(handle: *mut uv_handle_t) {
+    let state_ptr = uv_handle_get_data(handle) as *const LoopHookState;
+    Rc::increment_strong_count(state_ptr);
+    let state = Rc::from_raw(state_ptr);
+
+    if state.removed.get() {
+        return;
+    }
+
+    let mut callback = state.callback.borrow_mut();
+    callback();
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PollEvents {
+    pub readable: bool,
+    pub writable: bool,
+    pub disconnect: bool,
+}
+
+impl PollEvents {
+    pub fn readable() -> Self {
+        PollEvents {
+            readable: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn writable() -> Self {
+        PollEvents {
+            writable: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn readable_writable() -> Self {
+        PollEvents {
+            readable: true,
+            writable: true,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<PollEvents> for c_int {
+    fn from(value: PollEvents) -> Self {
+        let mut events = 0;
+        if value.readable {
+            events |= UV_READABLE;
+        }
+        if value.writable {
+            events |= UV_WRITABLE;
+        }
+        if value.disconnect {
+            events |= UV_DISCONNECT;
+        }
+        events
+    }
+}
+
+impl From<c_int> for PollEvents {
+    fn from(value: c_int) -> Self {
+        PollEvents {
+            readable: value & UV_READABLE != 0,
+            writable: value & UV_WRITABLE != 0,
+            disconnect: value & UV_DISCONNECT != 0,
+        }
+    }
+}
+
+type PollCallback = Box<dyn FnMut(&FdPoll, io::Result<PollEvents>)>;
+
+// Watches a raw fd (eventfd, pipe, socket...) for readiness on the loop thread.
+// The fd must be non-blocking and stays owned by the caller, stop the poll before closing it.
+pub fn poll_fd(
+    uws_loop: UwsLoop,
+    fd: RawFd,
+    interest: PollEvents,
+    cb: impl FnMut(&FdPoll, io::Result<PollEvents>) + 'static,
+) -> io::Result<FdPoll> {
+    unsafe {
+        let handle = alloc_handle(UV_POLL);
+        let code = uv_poll_init(uv_loop_of(uws_loop.loop_ptr), handle, fd);
+        if code < 0 {
+            free_handle_memory(handle, UV_POLL);
+            return Err(uv_error(code));
+        }
+
+        let state = Rc::new(FdPollState {
+            handle,
+            fd,
+            callback: RefCell::new(Box::new(cb)),
+            stopped: Cell::new(false),
+        });
+        uv_handle_set_data(handle, Rc::into_raw(state.clone()) as *mut c_void);
+        uv_unref(handle);
+
+        let poll = FdPoll { state };
+        if let Err(error) = poll.change(interest) {
+            poll.stop();
+            return Err(error);
+        }
+        Ok(poll)
+    }
+}
+
+fn uv_error(code: c_int) -> io::Error {
+    io::Error::from_raw_os_error(-code)
+}
+
+struct FdPollState {
+    handle: *mut uv_handle_t,
+    fd: RawFd,
+    callback: RefCell<PollCallback>,
+    stopped: Cell<bool>,
+}
+
+#[derive(Clone)]
+pub struct FdPoll {
+    state: Rc<FdPollState>,
+}
+
+impl FdPoll {
+    pub fn fd(&self) -> RawFd {
+        self.state.fd
+    }
+
+    /// An interest without any event is refused, `stop` ends the poll.
+    pub fn change(&self, interest: PollEvents) -> io::Result<()> {
+        if self.state.stopped.get() {
+            return Ok(());
+        }
+        if interest == PollEvents::default() {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+
+        let code =
+            unsafe { uv_poll_start(self.state.handle, interest.into(), Some(fd_poll_callback)) };
+        if code < 0 {
+            return Err(uv_error(code));
+        }
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        if self.state.stopped.replace(true) {
+            return;
+        }
+
+        unsafe {
+            let state_ptr = uv_handle_get_data(self.state.handle) as *const FdPollState;
+            uv_poll_stop(self.state.handle);
+            close_handle(self.state.handle, UV_POLL);
+            drop(Rc::from_raw(state_ptr));
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.state.stopped.get()
+    }
+}
+
+unsafe extern "C" fn fd_poll_callback(handle: *mut uv_handle_t, status: c_int, events: c_int) {
+    let state_ptr = uv_handle_get_data(handle) as *const FdPollState;
+    Rc::increment_strong_count(state_ptr);
+    let poll = FdPoll {
+        state: Rc::from_raw(state_ptr),
+    };
+
+    if poll.state.stopped.get() {
+        return;
+    }
+
+    let result = if status < 0 {
+        Err(uv_error(status))
+    } else {
+        Ok(events.into())
+    };
+
+    let mut callback = poll.state.callback.borrow_mut();
+    callback(&poll, result);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+
+    #[test]
+    fn poll_fd_gives_the_fd_back_on_an_invalid_interest() {
+        let (socket, _peer) = UnixStream::pair().unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let fd = socket.as_raw_fd();
+
+        match poll_fd(get_loop(), fd, PollEvents::default(), |_, _| {}) {
+            Err(error) => assert_eq!(error.kind(), io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("Polled for no event"),
+        }
+        // libuv refuses fds it still watches
+        let poll = poll_fd(get_loop(), fd, PollEvents::readable(), |_, _| {}).unwrap();
+        assert!(poll.is_active());
+        poll.stop();
+    }
+}
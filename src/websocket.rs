@@ -1,24 +1,75 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ffi::{c_char, c_int, c_void};
 use std::fmt::Debug;
+use std::net::{IpAddr, SocketAddr};
 use std::ptr::{null, null_mut};
 
 use libuwebsockets_sys::{
-    uws_websocket_t, uws_ws_close, uws_ws_cork, uws_ws_end, uws_ws_get_buffered_amount,
-    uws_ws_get_remote_address, uws_ws_get_remote_address_as_text, uws_ws_get_user_data,
+    us_socket_remote_port, us_socket_t, uws_websocket_t, uws_ws_close, uws_ws_cork, uws_ws_end,
+    uws_ws_get_buffered_amount, uws_ws_get_remote_address, uws_ws_get_user_data,
     uws_ws_is_subscribed, uws_ws_iterate_topics, uws_ws_publish, uws_ws_publish_with_options,
     uws_ws_send, uws_ws_send_first_fragment, uws_ws_send_first_fragment_with_opcode,
     uws_ws_send_fragment, uws_ws_send_last_fragment, uws_ws_send_with_options, uws_ws_subscribe,
     uws_ws_unsubscribe,
 };
 
-use crate::utils::{read_buf_from_ptr, read_str_from_ptr};
+use crate::proxy_protocol::ProxiedSource;
+use crate::utils::{ip_from_bytes, read_buf_from_ptr, read_str_from_ptr};
+
+// What the upgrade request knew about the client, kept while the WebSocket is open
+#[derive(Default)]
+pub(crate) struct UpgradedAddresses {
+    pub(crate) client_ip: Option<IpAddr>,
+    // The source address of the PROXY protocol header
+    pub(crate) proxied_address: Option<ProxiedSource>,
+}
+
+impl UpgradedAddresses {
+    fn is_empty(&self) -> bool {
+        self.client_ip.is_none() && self.proxied_address.is_none()
+    }
+}
+
+thread_local! {
+    // Keyed by the native socket
+    static UPGRADED_ADDRESSES: RefCell<HashMap<usize, UpgradedAddresses>> =
+        RefCell::new(HashMap::new());
+    // Handed from `HttpResponseStruct::upgrade` to the open handler, uWS calls it right away
+    static UPGRADING_ADDRESSES: RefCell<Option<UpgradedAddresses>> = const { RefCell::new(None) };
+}
+
+pub(crate) struct UpgradingAddresses;
+
+impl UpgradingAddresses {
+    pub(crate) fn set(addresses: UpgradedAddresses) -> Self {
+        UPGRADING_ADDRESSES.set((!addresses.is_empty()).then_some(addresses));
+        UpgradingAddresses
+    }
+}
+
+impl Drop for UpgradingAddresses {
+    fn drop(&mut self) {
+        UPGRADING_ADDRESSES.set(None);
+    }
+}
+
+// Called once the close handler is done with the socket
+pub(crate) fn forget_upgraded_addresses(native: *mut uws_websocket_t) {
+    UPGRADED_ADDRESSES.with(|addresses| {
+        let mut addresses = addresses.borrow_mut();
+        if !addresses.is_empty() {
+            addresses.remove(&(native as usize));
+        }
+    });
+}
 
 pub type WebSocket = WebSocketStruct<false>;
 pub type WebSocketSSL = WebSocketStruct<true>;
 
 #[derive(Clone)]
 pub struct WebSocketStruct<const SSL: bool> {
-    native: *mut uws_websocket_t,
+    pub(crate) native: *mut uws_websocket_t,
     pub(crate) cork_handler_ptr: Option<*mut dyn Fn()>,
     pub(crate) topics: Option<Vec<&'static str>>,
 }
@@ -250,26 +301,71 @@ impl<const SSL: bool> WebSocketStruct<SSL> {
         unsafe { uws_ws_get_buffered_amount(SSL as c_int, self.native) }
     }
 
-    pub fn get_remote_address(&self) -> &[u8] {
+    /// The address of the TCP peer, or the source address of the PROXY protocol header the
+    /// connection started with if the application listens with `ListenOptions::proxy_protocol`.
+    pub fn get_remote_address(&self) -> Option<IpAddr> {
+        if let Some(proxied_address) =
+            self.upgraded_addresses(|addresses| addresses.proxied_address)
+        {
+            return Some(proxied_address.ip);
+        }
         let mut buf: *const c_char = null_mut();
         let len = unsafe {
             uws_ws_get_remote_address(SSL as c_int, self.native, &mut buf as *mut *const c_char)
         };
 
-        unsafe { read_buf_from_ptr(buf, len) }
+        ip_from_bytes(unsafe { read_buf_from_ptr(buf, len) })
     }
 
-    pub fn get_remote_address_as_text(&self) -> &str {
-        let mut buf: *const c_char = null_mut();
-        let len = unsafe {
-            uws_ws_get_remote_address_as_text(
-                SSL as c_int,
-                self.native,
-                &mut buf as *mut *const c_char,
-            )
-        };
+    /// The port of the TCP peer, or the source port of the PROXY protocol header. None for v2
+    /// headers, uWS parses those and keeps their port to itself.
+    pub fn get_remote_port(&self) -> Option<u16> {
+        if let Some(proxied_address) =
+            self.upgraded_addresses(|addresses| addresses.proxied_address)
+        {
+            return proxied_address.port;
+        }
+        let port = unsafe { us_socket_remote_port(SSL as c_int, self.native as *mut us_socket_t) };
+        u16::try_from(port).ok()
+    }
+
+    pub fn get_remote_socket_addr(&self) -> Option<SocketAddr> {
+        Some(SocketAddr::new(
+            self.get_remote_address()?,
+            self.get_remote_port()?,
+        ))
+    }
 
-        unsafe { read_str_from_ptr(buf, len) }
+    /// The remote address like `203.0.113.7` or `2001:db8::1`, empty if it is unknown.
+    pub fn get_remote_address_as_text(&self) -> String {
+        self.get_remote_address()
+            .map(|ip| ip.to_string())
+            .unwrap_or_default()
+    }
+
+    fn upgraded_addresses<T>(
+        &self,
+        get: impl FnOnce(&UpgradedAddresses) -> Option<T>,
+    ) -> Option<T> {
+        UPGRADED_ADDRESSES.with(|addresses| {
+            addresses
+                .borrow()
+                .get(&(self.native as usize))
+                .and_then(get)
+        })
+    }
+
+    pub(crate) fn adopt_upgraded_addresses(&self) {
+        if let Some(addresses) = UPGRADING_ADDRESSES.take() {
+            UPGRADED_ADDRESSES.with(|all| all.borrow_mut().insert(self.native as usize, addresses));
+        }
+    }
+
+    /// The client address `Application::resolve_client_ip` found for the upgrade request, or
+    /// the remote address if there is no resolver.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.upgraded_addresses(|addresses| addresses.client_ip)
+            .or_else(|| self.get_remote_address())
     }
 
     pub fn get_user_data<T: Sized>(&self) -> Option<&mut T> {
@@ -312,7 +408,7 @@ impl From<u32> for SendStatus {
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum Opcode {
     Continuation,
     Text,